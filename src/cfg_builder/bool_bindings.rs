@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+use syn::visit::{self, Visit};
+use syn::{BinOp, Expr, ExprClosure, FnArg, Item, ItemFn, Local, Pat, Type};
+
+// Variables of a function holding booleans: 'bool' parameters and variables bound or assigned
+// boolean values, like 'ok' in 'let ok = x > 0'. The WP calculus substitutes a binding on the
+// paths through it, but a path starting after it keeps the variable, and Z3 has to know that
+// it is a Bool and not an Int there.
+pub fn bool_bindings(item_fn: &ItemFn) -> HashSet<String> {
+    let mut bindings = BoolBindings::default();
+    for input in &item_fn.sig.inputs {
+        if let FnArg::Typed(pat_type) = input {
+            bindings.bind_typed(&pat_type.pat, &pat_type.ty);
+        }
+    }
+    // 'let b = a' makes 'b' a boolean once 'a' is known to be one, repeat until stable
+    loop {
+        let known = bindings.0.len();
+        bindings.visit_block(&item_fn.block);
+        if bindings.0.len() == known {
+            return bindings.0;
+        }
+    }
+}
+
+#[derive(Default)]
+struct BoolBindings(HashSet<String>);
+
+impl BoolBindings {
+    fn bind_typed(&mut self, pat: &Pat, ty: &Type) {
+        if let (Pat::Ident(pat_ident), Type::Path(type_path)) = (pat, ty) {
+            if type_path.path.is_ident("bool") {
+                self.0.insert(pat_ident.ident.to_string());
+            }
+        }
+    }
+
+    fn bind_value(&mut self, target: &Expr, value: &Expr) {
+        if let Expr::Path(expr_path) = target {
+            if let Some(ident) = expr_path.path.get_ident() {
+                if self.is_bool_value(value) {
+                    self.0.insert(ident.to_string());
+                }
+            }
+        }
+    }
+
+    // Whether an expression evaluates to a boolean, as far as it can be told without types
+    fn is_bool_value(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Lit(expr_lit) => matches!(expr_lit.lit, syn::Lit::Bool(_)),
+            Expr::Path(expr_path) => expr_path
+                .path
+                .get_ident()
+                .is_some_and(|ident| self.0.contains(&ident.to_string())),
+            Expr::Paren(expr_paren) => self.is_bool_value(&expr_paren.expr),
+            Expr::Reference(expr_reference) => self.is_bool_value(&expr_reference.expr),
+            // '!' of an integer is its bitwise complement
+            Expr::Unary(expr_unary) => {
+                matches!(expr_unary.op, syn::UnOp::Not(_) | syn::UnOp::Deref(_))
+                    && self.is_bool_value(&expr_unary.expr)
+            }
+            Expr::Binary(expr_binary) => match expr_binary.op {
+                BinOp::Eq(_)
+                | BinOp::Ne(_)
+                | BinOp::Lt(_)
+                | BinOp::Le(_)
+                | BinOp::Gt(_)
+                | BinOp::Ge(_)
+                | BinOp::And(_)
+                | BinOp::Or(_) => true,
+                BinOp::BitXor(_) | BinOp::BitAnd(_) | BinOp::BitOr(_) => {
+                    self.is_bool_value(&expr_binary.left) || self.is_bool_value(&expr_binary.right)
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl Visit<'_> for BoolBindings {
+    fn visit_local(&mut self, local: &Local) {
+        match &local.pat {
+            Pat::Type(pat_type) => self.bind_typed(&pat_type.pat, &pat_type.ty),
+            Pat::Ident(pat_ident) => {
+                if let Some((_, init)) = &local.init {
+                    if self.is_bool_value(init) {
+                        self.0.insert(pat_ident.ident.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+        visit::visit_local(self, local);
+    }
+
+    fn visit_expr_assign(&mut self, assign: &syn::ExprAssign) {
+        self.bind_value(&assign.left, &assign.right);
+        visit::visit_expr_assign(self, assign);
+    }
+
+    fn visit_expr_closure(&mut self, _closure: &ExprClosure) {}
+
+    fn visit_item(&mut self, _item: &Item) {}
+}
+
+// Variables of a function holding floats: 'f32' and 'f64' parameters and variables, and
+// variables bound to float values, like 'avg' in 'let avg = sum as f64 / 2.0'. Z3 models
+// them as Reals instead of Ints.
+pub fn real_bindings(item_fn: &ItemFn) -> HashSet<String> {
+    let mut bindings = RealBindings::default();
+    for input in &item_fn.sig.inputs {
+        if let FnArg::Typed(pat_type) = input {
+            bindings.bind_typed(&pat_type.pat, &pat_type.ty);
+        }
+    }
+    bindings.visit_block(&item_fn.block);
+    bindings.0
+}
+
+fn is_float_type(ty: &Type) -> bool {
+    matches!(ty, Type::Path(type_path)
+        if type_path.path.is_ident("f64") || type_path.path.is_ident("f32"))
+}
+
+#[derive(Default)]
+struct RealBindings(HashSet<String>);
+
+impl RealBindings {
+    fn bind_typed(&mut self, pat: &Pat, ty: &Type) {
+        if let Pat::Ident(pat_ident) = pat {
+            if is_float_type(ty) {
+                self.0.insert(pat_ident.ident.to_string());
+            }
+        }
+    }
+
+    // Whether an expression evaluates to a float, as far as it can be told without types
+    fn is_real_value(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Lit(expr_lit) => matches!(expr_lit.lit, syn::Lit::Float(_)),
+            Expr::Cast(expr_cast) => is_float_type(&expr_cast.ty),
+            Expr::Path(expr_path) => expr_path
+                .path
+                .get_ident()
+                .is_some_and(|ident| self.0.contains(&ident.to_string())),
+            Expr::Paren(expr_paren) => self.is_real_value(&expr_paren.expr),
+            Expr::Unary(expr_unary) => {
+                matches!(expr_unary.op, syn::UnOp::Neg(_)) && self.is_real_value(&expr_unary.expr)
+            }
+            Expr::Binary(expr_binary) => {
+                matches!(
+                    expr_binary.op,
+                    BinOp::Add(_) | BinOp::Sub(_) | BinOp::Mul(_) | BinOp::Div(_)
+                ) && (self.is_real_value(&expr_binary.left)
+                    || self.is_real_value(&expr_binary.right))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Visit<'_> for RealBindings {
+    fn visit_local(&mut self, local: &Local) {
+        match &local.pat {
+            Pat::Type(pat_type) => self.bind_typed(&pat_type.pat, &pat_type.ty),
+            Pat::Ident(pat_ident) => {
+                if let Some((_, init)) = &local.init {
+                    if self.is_real_value(init) {
+                        self.0.insert(pat_ident.ident.to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+        visit::visit_local(self, local);
+    }
+
+    fn visit_expr_closure(&mut self, _closure: &ExprClosure) {}
+
+    fn visit_item(&mut self, _item: &Item) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bool_bindings, real_bindings};
+    use crate::{path_outcomes, VerifyOutcome};
+
+    #[test]
+    fn bound_comparisons_are_booleans() {
+        let source = "fn f(x: i32) -> bool { pre!(x > 1); let ok = x > 0; post!(ok); ok }
+fn g(n: i32) -> i32 { pre!(n >= 0); let mut i = 0; let done = n == 0; \
+            while i < n { invariant!(i <= n && (done == (n == 0))); i = i + 1; } \
+            post!(done || i == n); i }";
+        let file = syn::parse_file(source).unwrap();
+        let syn::Item::Fn(g) = &file.items[1] else {
+            panic!("expected a function");
+        };
+        assert_eq!(bool_bindings(g), ["done".to_string()].into());
+        let outcomes = path_outcomes(source);
+        assert_eq!(outcomes.len(), 4);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn parameters_have_the_sort_of_their_type() {
+        let source = "fn f(flag: bool, n: i32, x: f64) -> f64 { pre!(flag && n > 0 && x > 0.5); \
+            let avg = x / 2.0; post!(flag && n >= 1 && avg > 0.25); avg }";
+        let file = syn::parse_file(source).unwrap();
+        let syn::Item::Fn(f) = &file.items[0] else {
+            panic!("expected a function");
+        };
+        assert_eq!(bool_bindings(f), ["flag".to_string()].into());
+        assert_eq!(
+            real_bindings(f),
+            ["x".to_string(), "avg".to_string()].into()
+        );
+        assert!(matches!(path_outcomes(source)[..], [VerifyOutcome::Valid]));
+        // 'x' is a real, so being above 0.5 doesn't make it at least 1
+        let real = "fn f(flag: bool, x: f64) -> f64 { pre!(flag && x > 0.5); post!(x >= 1.0); x }";
+        assert!(path_outcomes(real)[0].is_invalid());
+    }
+}
@@ -1,418 +1,906 @@
-use crate::cfg_builder::node::CfgNode;
-/// This module is responsible for building the Control Flow Graph (CFG) structure for Rust methods.
-///
-/// The 'CfgBuilder' struct provides functionalities to:
-/// - Construct a CFG from Rust functions annotated with macros like 'pre!', 'post!', and 'invariant!'.
-/// - Add nodes and edges representing statements, conditions, and control flow.
-/// - Generate a DOT representation of the CFG for visualization.
-/// - Process various Rust expressions such as loops, conditions, and macros to build the CFG.
-///
-/// This module relies on the 'petgraph' crate for graph manipulation and the 'syn' crate for parsing Rust code.
-use petgraph::graph::{DiGraph, NodeIndex};
-use petgraph::visit::EdgeRef;
-use quote::quote;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::fs;
-use syn::{
-    visit::{self, Visit},
-    Block, Expr, File as SynFile, ItemFn, Stmt,
-};
-
-// TODO add external method conditions when used.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct ExternalMethod {
-    pub name: String,
-    pub preconditions: Vec<String>,
-    pub postconditions: Vec<String>,
-}
-
-// List of external methods
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ExternalMethods {
-    pub external_methods: Vec<ExternalMethod>,
-}
-
-// Main struct of the CfgBuilder
-pub struct CfgBuilder {
-    pub graph: DiGraph<CfgNode, String>, // Directed graph representing the CFG
-    pub current_node: Option<NodeIndex>, // current node being processed
-    pub next_edge_label: Option<String>,
-    pub external_conditions: ExternalMethods,
-    pub postconditions: Vec<CfgNode>,
-}
-
-impl CfgBuilder {
-    // Create new instance of CfgBuilder
-    pub fn new() -> Self {
-        // Attempt to load external conditions from the config file
-        let external_conditions =
-            match Self::parse_external_definitions("src/config/conditions.json") {
-                Ok(conditions) => conditions,
-                Err(e) => {
-                    eprintln!("Failed to load external conditions: {}", e);
-                    ExternalMethods {
-                        external_methods: vec![],
-                    }
-                }
-            };
-
-        // Initialize the graph and fields
-        CfgBuilder {
-            graph: DiGraph::new(),
-            current_node: None,
-            next_edge_label: None,
-            external_conditions,
-            postconditions: Vec::new(),
-        }
-    }
-
-    // Method called to build the CFG
-    pub fn build_cfg(&mut self, ast: &SynFile) {
-        // Visit the AST to build the CFG nodes and edges
-        self.visit_file(ast);
-
-        // Post-process the CFG to handle merges and cleanup
-        self.post_process();
-    }
-
-    // Parse external conditions if there are any
-    pub fn parse_external_definitions(
-        file_path: &str,
-    ) -> Result<ExternalMethods, Box<dyn std::error::Error>> {
-        if !std::path::Path::new(file_path).exists() {
-            eprintln!("Warning: External conditions file not found. Using empty conditions.");
-            return Ok(ExternalMethods {
-                external_methods: vec![],
-            });
-        }
-
-        let file_content = fs::read_to_string(file_path)?;
-        let external_methods: ExternalMethods = serde_json::from_str(&file_content)?;
-        Ok(external_methods)
-    }
-
-    // Method used to add postconditions at the end of graph
-    pub fn add_postconditions(&mut self) {
-        let postconditions = self.postconditions.clone();
-        for postcondition in postconditions {
-            self.add_node(postcondition);
-        }
-        self.postconditions.clear();
-    }
-
-    // Adds a node to the graph and connects it to the current node
-    pub fn add_node(&mut self, node: CfgNode) -> NodeIndex {
-        let index = self.graph.add_node(node);
-        if let Some(current) = self.current_node {
-            // Use the label for the next edge if available
-            let label = self
-                .next_edge_label
-                .clone()
-                .unwrap_or_else(|| "".to_string());
-            self.graph.add_edge(current, index, label);
-            // Reset the edge label
-            self.next_edge_label = None;
-        }
-        self.current_node = Some(index);
-        index
-    }
-
-    // Add an isolated node (no edge)
-    pub fn add_node_without_edge(&mut self, node: CfgNode) -> NodeIndex {
-        let index = self.graph.add_node(node);
-        self.current_node = Some(index);
-        index
-    }
-
-    // Adds an edge between two nodes with a specified label
-    pub fn add_edge_with_label(&mut self, from: NodeIndex, to: NodeIndex, label: String) {
-        self.graph.add_edge(from, to, label);
-    }
-
-    // Convert CFG to dot format
-    pub fn to_dot(&self) -> String {
-        let mut dot_string = String::new();
-        dot_string.push_str("digraph G {\n");
-        for node in self.graph.node_indices() {
-            let cfg_node = &self.graph[node];
-            // Skip floating invariants
-            if let CfgNode::Invariant(_, _) = cfg_node {
-                let has_incoming = self
-                    .graph
-                    .edges_directed(node, petgraph::Direction::Incoming)
-                    .count()
-                    > 0;
-                let has_outgoing = self
-                    .graph
-                    .edges_directed(node, petgraph::Direction::Outgoing)
-                    .count()
-                    > 0;
-
-                // If invariant is floating (no incoming or outgoing edges), skip it
-                if !has_incoming || !has_outgoing {
-                    continue;
-                }
-            }
-            dot_string.push_str(&cfg_node.format_dot(node.index()));
-            dot_string.push('\n');
-        }
-        for edge in self.graph.edge_references() {
-            let source = edge.source().index();
-            let target = edge.target().index();
-            let label = edge.weight();
-            dot_string.push_str(&format!(
-                "{} -> {} [label=\"{}\"];\n",
-                source, target, label
-            ));
-        }
-        dot_string.push_str("}\n");
-        dot_string
-    }
-
-    pub fn clean_up_formatting(input: &str) -> String {
-        let re = Regex::new(r"\s*([\(\)\[\]!\.,;])\s*").unwrap();
-        let cleaned = re.replace_all(input, "$1").to_string();
-
-        cleaned.replace("vec! [", "vec![").replace("+ ", " + ")
-    }
-
-    pub fn format_condition(&self, expr: &Box<Expr>) -> String {
-        let raw_string = quote!(#expr).to_string();
-        Self::clean_up_formatting(&raw_string)
-    }
-
-    // Post process and merge CFG 'empty' nodes used for converging edges
-    pub fn post_process(&mut self) {
-        let mut merge_nodes_to_process: Vec<NodeIndex> = self
-            .graph
-            .node_indices()
-            .filter(|&n| matches!(self.graph[n], CfgNode::MergePoint))
-            .collect();
-
-        while let Some(merge_node) = merge_nodes_to_process.pop() {
-            // Check if the merge node has edges (i.e., is still part of the graph)
-            if self.graph.edges(merge_node).count() == 0 {
-                continue;
-            }
-
-            // Find outgoing edges of the merge node
-            let edges: Vec<_> = self.graph.edges(merge_node).collect();
-
-            if edges.len() == 1 {
-                let target = edges[0].target();
-                if matches!(self.graph[target], CfgNode::MergePoint) {
-                    // If the target is another merge node, merge them
-                    self.merge_merge_nodes(merge_node, target);
-                    merge_nodes_to_process.push(target);
-                } else {
-                    // If the target is not a merge node, redirect incoming edges and remove the merge node
-                    self.redirect_edges_and_remove(merge_node, target);
-                }
-            }
-        }
-        // Clean up formatting in the node labels
-        for node in self.graph.node_indices() {
-            if let CfgNode::Condition(label, _) | CfgNode::Statement(label, _) =
-                &mut self.graph[node]
-            {
-                *label = CfgBuilder::clean_up_formatting(label);
-            }
-        }
-    }
-
-    // merge converging nodes with other converging nodes
-    fn merge_merge_nodes(&mut self, source: NodeIndex, target: NodeIndex) {
-        let incoming_edges: Vec<_> = self
-            .graph
-            .edges_directed(source, petgraph::Direction::Incoming)
-            .map(|e| (e.source(), e.weight().clone()))
-            .collect();
-
-        for (source_of_edge, weight) in incoming_edges {
-            self.graph.add_edge(source_of_edge, target, weight);
-        }
-        self.graph.remove_node(source);
-    }
-
-    // used to redirect edges of merged nodes
-    fn redirect_edges_and_remove(&mut self, source: NodeIndex, new_target: NodeIndex) {
-        let incoming_edges: Vec<_> = self
-            .graph
-            .edges_directed(source, petgraph::Direction::Incoming)
-            .map(|e| (e.source(), e.weight().clone()))
-            .collect();
-
-        for (source_of_edge, weight) in incoming_edges {
-            self.graph.add_edge(source_of_edge, new_target, weight);
-        }
-
-        self.graph.remove_node(source);
-    }
-
-    fn format_macro_args(&self, tokens: &proc_macro2::TokenStream) -> String {
-        let tokens_str = tokens.to_string();
-        tokens_str
-            .trim_start_matches("!(")
-            .trim_end_matches(')')
-            .trim_matches(|c| c == '"' || c == '\'')
-            .to_string()
-    }
-}
-
-impl Visit<'_> for CfgBuilder {
-    // Process Rust source file.
-    fn visit_file(&mut self, i: &SynFile) {
-        visit::visit_file(self, i);
-    }
-
-    // Handle function definitions and statements
-    fn visit_item_fn(&mut self, i: &ItemFn) {
-        let func_name = i.sig.ident.to_string();
-
-        // Check if the function contains any relevant macros
-        let mut contains_macros = false;
-        for stmt in &i.block.stmts {
-            if let Stmt::Semi(expr, _) = stmt {
-                if let Expr::Macro(expr_macro) = expr {
-                    if let Some(macro_ident) = expr_macro.mac.path.get_ident() {
-                        let macro_name = macro_ident.to_string();
-                        if ["pre", "post", "invariant", "build_cfg"].contains(&macro_name.as_str())
-                        {
-                            contains_macros = true;
-                            break;
-                        }
-                    }
-                }
-            }
-        }
-
-        // Skip this function if no relevant macros are found
-        if !contains_macros {
-            return;
-        }
-
-        let func_node = self.add_node(CfgNode::new_function(func_name.clone(), i.clone()));
-
-        self.current_node = Some(func_node);
-
-        // Process each statement in function body
-        for stmt in &i.block.stmts {
-            match stmt {
-                Stmt::Semi(expr, _) => {
-                    // Statement usually ending with semicolumn
-                    // Handle macro expressions
-                    if let Expr::Macro(expr_macro) = expr {
-                        if let Some(macro_ident) = expr_macro.mac.path.get_ident() {
-                            let macro_name = macro_ident.to_string();
-                            if macro_name.as_str() == "build_cfg" {
-                                continue; // Skip processing this macro
-                            }
-                            let macro_args = self.format_macro_args(&expr_macro.mac.tokens);
-                            // handle annotation macros
-                            let node = match macro_name.as_str() {
-                                "pre" => CfgNode::new_precondition(
-                                    macro_args.clone(),
-                                    Expr::Macro(expr_macro.clone()),
-                                ),
-                                "post" => {
-                                    let post_node = CfgNode::new_postcondition(
-                                        macro_args.clone(),
-                                        Expr::Macro(expr_macro.clone()),
-                                    );
-                                    // add postconditions to vec to later merge them at the end of the CFG.
-                                    self.postconditions.push(post_node.clone());
-                                    post_node
-                                }
-                                "invariant" => CfgNode::new_invariant(
-                                    macro_args.clone(),
-                                    Expr::Macro(expr_macro.clone()),
-                                ),
-                                _ => {
-                                    let expr_str = quote!(#i).to_string();
-                                    CfgNode::new_statement(
-                                        expr_str,
-                                        Stmt::Expr(Expr::Macro(expr_macro.clone())),
-                                    )
-                                }
-                            };
-                            if macro_name.as_str() != "post" {
-                                self.add_node(node);
-                            }
-                        } else {
-                            self.visit_expr(expr);
-                        }
-                    } else {
-                        self.visit_expr(expr);
-                    }
-                }
-                _ => self.visit_stmt(stmt),
-            }
-        }
-        self.add_postconditions();
-
-        self.current_node = None;
-    }
-
-    // Processes Rust expressions (loops, conditions, macros, etc.)
-    fn visit_expr(&mut self, i: &Expr) {
-        match i {
-            Expr::If(expr_if) => self.handle_if_statement(expr_if),
-            Expr::While(expr_while) => self.handle_while_loop(expr_while),
-            Expr::ForLoop(expr_for) => self.handle_for_loop(expr_for),
-            Expr::Return(expr_return) => {
-                self.handle_return_statement(expr_return);
-            }
-            Expr::Call(expr_call) => self.handle_call(expr_call),
-            Expr::MethodCall(expr_method_call) => self.handle_method_call(expr_method_call),
-            Expr::Macro(expr_macro) => {
-                self.process_macro(expr_macro); // method from the handle_macro module
-            }
-            Expr::Array(expr_array) => {
-                for elem in &expr_array.elems {
-                    self.visit_expr(elem); // Recursively visit to catch nested macros
-                }
-            }
-            _ => {
-                // Handling invariant macro
-                if let Expr::Macro(expr_macro) = i {
-                    if let Some(macro_ident) = expr_macro.mac.path.get_ident() {
-                        if macro_ident == "invariant" {
-                            // Handling invariant
-                            let invariant_str = self.format_macro_args(&expr_macro.mac.tokens);
-                            self.add_node(CfgNode::new_invariant(
-                                invariant_str,
-                                Expr::Macro(expr_macro.clone()),
-                            ));
-                            return;
-                        }
-                    }
-                }
-                // else a simple expression.
-                let expr_str = quote!(#i).to_string();
-                let call_statement = Stmt::Expr(i.clone());
-                self.add_node(CfgNode::new_statement(expr_str, call_statement));
-            }
-        }
-    }
-    // Method to visit code blocks
-    fn visit_block(&mut self, i: &Block) {
-        for stmt in &i.stmts {
-            self.visit_stmt(stmt);
-        }
-    }
-    fn visit_stmt(&mut self, i: &Stmt) {
-        match i {
-            Stmt::Local(local) => {
-                // Handle local variable declarations
-                let local_str = format!("{}", quote!(#local));
-                self.add_node(CfgNode::new_statement(
-                    local_str,
-                    Stmt::Local(local.clone()),
-                ));
-            }
-            Stmt::Expr(expr) | Stmt::Semi(expr, _) => self.visit_expr(expr),
-            _ => visit::visit_stmt(self, i),
-        }
-    }
-}
+use crate::cfg_builder::bool_bindings::{bool_bindings, real_bindings};
+use crate::cfg_builder::conjuncts::join_conjuncts;
+use crate::cfg_builder::contracts::FunctionContract;
+use crate::cfg_builder::handle_return::returned_binding;
+use crate::cfg_builder::node::CfgNode;
+use crate::cfg_builder::ssa::rename_shadowed_bindings;
+/// This module is responsible for building the Control Flow Graph (CFG) structure for Rust methods.
+///
+/// The 'CfgBuilder' struct provides functionalities to:
+/// - Construct a CFG from Rust functions annotated with macros like 'pre!', 'post!', and 'invariant!'.
+/// - Add nodes and edges representing statements, conditions, and control flow.
+/// - Generate a DOT representation of the CFG for visualization.
+/// - Process various Rust expressions such as loops, conditions, and macros to build the CFG.
+///
+/// This module relies on the 'petgraph' crate for graph manipulation and the 'syn' crate for parsing Rust code.
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::visit::EdgeRef;
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
+use quote::{quote, quote_spanned};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use syn::{
+    spanned::Spanned,
+    visit::{self, Visit},
+    Block, Expr, File as SynFile, ItemFn, Stmt,
+};
+
+// Default location of the external method conditions, relative to the working directory
+pub const DEFAULT_CONDITIONS_PATH: &str = "src/config/conditions.json";
+
+// TODO add external method conditions when used.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExternalMethod {
+    pub name: String,
+    pub preconditions: Vec<String>,
+    pub postconditions: Vec<String>,
+}
+
+// List of external methods
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExternalMethods {
+    pub external_methods: Vec<ExternalMethod>,
+}
+
+impl ExternalMethods {
+    // Every method has a name and its conditions are Rust expressions
+    fn validate(&self) -> Result<(), String> {
+        for (i, method) in self.external_methods.iter().enumerate() {
+            if method.name.trim().is_empty() {
+                return Err(format!("external method {} has an empty name", i + 1));
+            }
+            let conditions = [
+                ("precondition", &method.preconditions),
+                ("postcondition", &method.postconditions),
+            ];
+            for (kind, conditions) in conditions {
+                for condition in conditions {
+                    syn::parse_str::<Expr>(condition).map_err(|e| {
+                        format!(
+                            "{} '{}' of '{}' is not a valid expression: {}",
+                            kind, condition, method.name, e
+                        )
+                    })?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Loop being built, targeted by 'break' and 'continue' in its body
+pub struct LoopContext {
+    pub label: Option<String>,       // loop label like 'outer, if any
+    pub loop_back_node: NodeIndex,   // node 'continue' jumps back to
+    pub break_nodes: Vec<NodeIndex>, // 'break' nodes to connect to the loop exit
+    pub increment: Option<CfgNode>,  // step of a loop over a range, also taken on 'continue'
+}
+
+// Main struct of the CfgBuilder
+pub struct CfgBuilder {
+    pub graph: DiGraph<CfgNode, String>, // Directed graph representing the CFG
+    pub current_node: Option<NodeIndex>, // current node being processed
+    pub next_edge_label: Option<String>,
+    pub external_conditions: ExternalMethods,
+    pub postconditions: Vec<CfgNode>,
+    pub loop_stack: Vec<LoopContext>, // enclosing loops, innermost last
+    pub function_filter: Option<String>, // only build the CFG of this function, if set
+    pub annotated_functions: Vec<String>, // names of all functions with annotation macros
+    pub function_lines: Vec<(String, usize, usize)>, // first and last line of each function's CFG
+    pub bool_vars: HashMap<String, HashSet<String>>, // variables holding booleans, by function name
+    pub real_vars: HashMap<String, HashSet<String>>, // variables holding floats, by function name
+    pub contracts: HashMap<String, FunctionContract>, // contracts of the file's functions, by name
+    pub constants: Vec<(String, Expr)>, // values of the file's constants, substituted into the conditions
+    pub return_nodes: Vec<NodeIndex>,   // ends of the returns of the current function
+    pub returned_binding: Option<String>, // variable the current function returns at its end
+    pub try_count: usize,               // '?' operators desugared so far, to name their flags
+    pub de_morgan: bool, // negate false branch conditions with De Morgan's laws instead of '!(...)'
+    pub extra_postconditions: Vec<Expr>, // added to every verified function, e.g. from the command line
+}
+
+impl CfgBuilder {
+    // Create new instance of CfgBuilder
+    pub fn new() -> Self {
+        // Attempt to load external conditions from the default config file
+        Self::with_default_config().unwrap_or_else(|e| {
+            log::warn!("Failed to load external conditions: {}", e);
+            Self::with_external_methods(Vec::new())
+        })
+    }
+
+    // Like 'new', but an invalid default config file is an error instead of being ignored,
+    // so a typo in it can't silently drop all external conditions. A missing one is fine.
+    pub fn with_default_config() -> Result<Self, Box<dyn std::error::Error>> {
+        let external_conditions = Self::parse_external_definitions(DEFAULT_CONDITIONS_PATH)?;
+        Ok(Self::with_external_conditions(external_conditions))
+    }
+
+    // Create new instance of CfgBuilder loading external conditions from an explicit config file.
+    // Unlike 'new', a missing or unreadable file is an error instead of falling back to no conditions.
+    pub fn with_config_path(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if !path.exists() {
+            return Err(format!("External conditions file not found: {}", path.display()).into());
+        }
+        let external_conditions = Self::parse_external_definitions(path)?;
+        Ok(Self::with_external_conditions(external_conditions))
+    }
+
+    // Create new instance of CfgBuilder with the given external methods, without reading any
+    // conditions file
+    pub fn with_external_methods(external_methods: Vec<ExternalMethod>) -> Self {
+        Self::with_external_conditions(ExternalMethods { external_methods })
+    }
+
+    fn with_external_conditions(external_conditions: ExternalMethods) -> Self {
+        // Initialize the graph and fields
+        CfgBuilder {
+            graph: DiGraph::new(),
+            current_node: None,
+            next_edge_label: None,
+            external_conditions,
+            postconditions: Vec::new(),
+            loop_stack: Vec::new(),
+            function_filter: None,
+            annotated_functions: Vec::new(),
+            function_lines: Vec::new(),
+            bool_vars: HashMap::new(),
+            real_vars: HashMap::new(),
+            contracts: HashMap::new(),
+            constants: Vec::new(),
+            return_nodes: Vec::new(),
+            returned_binding: None,
+            try_count: 0,
+            de_morgan: false,
+            extra_postconditions: Vec::new(),
+        }
+    }
+
+    // Registers the contract of an external method, replacing the one with the same name if any
+    pub fn add_external_method(&mut self, external_method: ExternalMethod) {
+        let external_methods = &mut self.external_conditions.external_methods;
+        match external_methods
+            .iter_mut()
+            .find(|m| m.name == external_method.name)
+        {
+            Some(existing) => *existing = external_method,
+            None => external_methods.push(external_method),
+        }
+    }
+
+    // Method called to build the CFG
+    pub fn build_cfg(&mut self, ast: &SynFile) {
+        let ast = &join_conjuncts(ast);
+        // Calls to the file's annotated functions use their contracts
+        self.contracts = Self::collect_contracts(ast);
+        self.constants = self.collect_constants(ast);
+
+        // Visit the AST to build the CFG nodes and edges
+        self.visit_file(ast);
+
+        // Post-process the CFG to handle merges and cleanup
+        self.post_process();
+    }
+
+    // Verification conditions of the basic paths of a source, in path order, without solving
+    // them, e.g. to check them with another solver
+    pub fn verification_conditions(
+        source: &str,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let ast = syn::parse_file(source)?;
+        let mut builder = Self::with_default_config()?;
+        builder.build_cfg(&ast);
+        let basic_paths = builder.generate_basic_paths();
+        Ok(builder.apply_wp_calculus(&basic_paths))
+    }
+
+    // Parse external conditions if there are any
+    pub fn parse_external_definitions<P: AsRef<Path>>(
+        file_path: P,
+    ) -> Result<ExternalMethods, Box<dyn std::error::Error>> {
+        let file_path = file_path.as_ref();
+        if !file_path.exists() {
+            log::warn!("External conditions file not found. Using empty conditions.");
+            return Ok(ExternalMethods {
+                external_methods: vec![],
+            });
+        }
+
+        let file_content = fs::read_to_string(file_path)?;
+        // serde_json errors tell the line and column
+        let external_methods: ExternalMethods = serde_json::from_str(&file_content)
+            .map_err(|e| format!("Invalid conditions file {}: {}", file_path.display(), e))?;
+        external_methods
+            .validate()
+            .map_err(|e| format!("Invalid conditions file {}: {}", file_path.display(), e))?;
+        Ok(external_methods)
+    }
+
+    // Method used to add postconditions at the end of graph.
+    // Each postcondition is a separate goal: they all follow the end of the body side by side,
+    // so every path to the end is checked against each of them on its own, instead of one
+    // postcondition only being assumed to prove the next.
+    pub fn add_postconditions(&mut self) {
+        let end_node = self.current_node;
+        let postconditions = std::mem::take(&mut self.postconditions);
+        // Paths follow the newest edges first, so the last postcondition is added first
+        // for their paths to come in source order
+        for postcondition in postconditions.into_iter().rev() {
+            self.current_node = end_node;
+            self.add_node(postcondition);
+        }
+    }
+
+    // 'item_fn' with a 'post!' for each extra postcondition. Postconditions hold at the end of
+    // the body wherever they are written, so they are put first. Not being in the file, they
+    // are located at the name of the function.
+    fn with_extra_postconditions(&self, item_fn: &ItemFn) -> ItemFn {
+        let mut item_fn = item_fn.clone();
+        let span = item_fn.sig.ident.span();
+        for cond in self.extra_postconditions.iter().rev() {
+            let cond = respanned(quote!(#cond), span);
+            let post = syn::parse2(quote_spanned!(span=> post!(#cond);))
+                .expect("Failed to build extra postcondition");
+            item_fn.block.stmts.insert(0, post);
+        }
+        item_fn
+    }
+
+    // Adds a node to the graph and connects it to the current node
+    pub fn add_node(&mut self, node: CfgNode) -> NodeIndex {
+        let index = self.graph.add_node(node);
+        if let Some(current) = self.current_node {
+            // Use the label for the next edge if available
+            let label = self
+                .next_edge_label
+                .clone()
+                .unwrap_or_else(|| "".to_string());
+            self.graph.add_edge(current, index, label);
+            // Reset the edge label
+            self.next_edge_label = None;
+        }
+        self.current_node = Some(index);
+        index
+    }
+
+    // Add an isolated node (no edge)
+    pub fn add_node_without_edge(&mut self, node: CfgNode) -> NodeIndex {
+        let index = self.graph.add_node(node);
+        self.current_node = Some(index);
+        index
+    }
+
+    // Adds an edge between two nodes with a specified label
+    pub fn add_edge_with_label(&mut self, from: NodeIndex, to: NodeIndex, label: String) {
+        self.graph.add_edge(from, to, label);
+    }
+
+    // Convert CFG to dot format
+    pub fn to_dot(&self) -> String {
+        let mut dot_string = String::new();
+        dot_string.push_str("digraph G {\n");
+        self.push_dot_nodes(&mut dot_string);
+        for edge in self.graph.edge_references() {
+            let source = edge.source().index();
+            let target = edge.target().index();
+            let label = edge.weight();
+            dot_string.push_str(&format!(
+                "{} -> {} [label=\"{}\"];\n",
+                source, target, label
+            ));
+        }
+        dot_string.push_str("}\n");
+        dot_string
+    }
+
+    // Plain text listing of the CFG: each node with its index, variant and label,
+    // followed by its outgoing edges and their labels
+    pub fn dump(&self) -> String {
+        let mut dump = String::new();
+        for node in self.graph.node_indices() {
+            let cfg_node = &self.graph[node];
+            dump.push_str(&format!(
+                "{} {}: {}\n",
+                node.index(),
+                cfg_node.variant_name(),
+                cfg_node.label()
+            ));
+            // petgraph lists outgoing edges newest first
+            let mut edges: Vec<_> = self.graph.edges(node).collect();
+            edges.reverse();
+            for edge in edges {
+                if edge.weight().is_empty() {
+                    dump.push_str(&format!("    -> {}\n", edge.target().index()));
+                } else {
+                    dump.push_str(&format!(
+                        "    -> {} [{}]\n",
+                        edge.target().index(),
+                        edge.weight()
+                    ));
+                }
+            }
+        }
+        dump
+    }
+
+    // DOT lines of all nodes of the CFG
+    pub fn push_dot_nodes(&self, dot_string: &mut String) {
+        for node in self.graph.node_indices() {
+            let cfg_node = &self.graph[node];
+            // Skip floating invariants
+            if let CfgNode::Invariant(_, _) = cfg_node {
+                let has_incoming = self
+                    .graph
+                    .edges_directed(node, petgraph::Direction::Incoming)
+                    .count()
+                    > 0;
+                let has_outgoing = self
+                    .graph
+                    .edges_directed(node, petgraph::Direction::Outgoing)
+                    .count()
+                    > 0;
+
+                // If invariant is floating (no incoming or outgoing edges), skip it
+                if !has_incoming || !has_outgoing {
+                    continue;
+                }
+            }
+            dot_string.push_str(&cfg_node.format_dot(node.index()));
+            dot_string.push('\n');
+        }
+    }
+
+    pub fn clean_up_formatting(input: &str) -> String {
+        let re = Regex::new(r"\s*([\(\)\[\]!\.,;])\s*").unwrap();
+        let cleaned = re.replace_all(input, "$1").to_string();
+
+        cleaned.replace("vec! [", "vec![").replace("+ ", " + ")
+    }
+
+    pub fn format_condition(&self, expr: &Box<Expr>) -> String {
+        let raw_string = quote!(#expr).to_string();
+        Self::clean_up_formatting(&raw_string)
+    }
+
+    // Post process and merge CFG 'empty' nodes used for converging edges.
+    // Removing a node moves the last node to its index, so the merge nodes are looked up again
+    // after each removal.
+    pub fn post_process(&mut self) {
+        while let Some(merge_node) = self
+            .graph
+            .node_indices()
+            .find(|&n| self.is_removable_merge_node(n))
+        {
+            self.bypass_and_remove(merge_node);
+        }
+        self.split_parallel_edges();
+        // Clean up formatting in the node labels
+        for node in self.graph.node_indices() {
+            if let CfgNode::Condition(label, _) | CfgNode::Statement(label, _) =
+                &mut self.graph[node]
+            {
+                *label = CfgBuilder::clean_up_formatting(label);
+            }
+        }
+    }
+
+    // Parallel edges (e.g. the 'true' and 'false' edges of an 'if' with an empty body) would yield
+    // identical node paths, so route all but the first through their own merge point
+    fn split_parallel_edges(&mut self) {
+        let mut seen = HashSet::new();
+        let parallel: Vec<(NodeIndex, NodeIndex, String)> = self
+            .graph
+            .edge_references()
+            .filter(|e| !seen.insert((e.source(), e.target())))
+            .map(|e| (e.source(), e.target(), e.weight().clone()))
+            .collect();
+
+        for (source, target, label) in parallel {
+            if let Some(edge) = self
+                .graph
+                .edges_connecting(source, target)
+                .find(|e| *e.weight() == label)
+                .map(|e| e.id())
+            {
+                self.graph.remove_edge(edge);
+                let merge = self.graph.add_node(CfgNode::MergePoint);
+                self.graph.add_edge(source, merge, label);
+                self.graph.add_edge(merge, target, "".to_string());
+            }
+        }
+    }
+
+    // A merge point can be replaced by edges from each of its sources to each of its targets,
+    // unless both sides have labels, e.g. a 'false' edge into it and a 'back to loop' edge out
+    // of it: the merge point is kept so the path keeps both. A merge point with incoming edges
+    // but no outgoing ones ends the function and is kept too.
+    fn is_removable_merge_node(&self, node: NodeIndex) -> bool {
+        if !matches!(self.graph[node], CfgNode::MergePoint) {
+            return false;
+        }
+        let has_labels = |direction| {
+            self.graph
+                .edges_directed(node, direction)
+                .any(|edge| !edge.weight().is_empty())
+        };
+        let has_edges = |direction| self.graph.edges_directed(node, direction).next().is_some();
+        if !has_edges(petgraph::Direction::Outgoing) {
+            return !has_edges(petgraph::Direction::Incoming);
+        }
+        !has_labels(petgraph::Direction::Incoming) || !has_labels(petgraph::Direction::Outgoing)
+    }
+
+    // Connects the sources of a merge point to its targets and removes it
+    fn bypass_and_remove(&mut self, merge_node: NodeIndex) {
+        let incoming: Vec<_> = self
+            .graph
+            .edges_directed(merge_node, petgraph::Direction::Incoming)
+            .map(|e| (e.source(), e.weight().clone()))
+            .collect();
+        let outgoing: Vec<_> = self
+            .graph
+            .edges(merge_node)
+            .map(|e| (e.target(), e.weight().clone()))
+            .collect();
+
+        for (source, incoming_label) in &incoming {
+            for (target, outgoing_label) in &outgoing {
+                let label = if incoming_label.is_empty() {
+                    outgoing_label
+                } else {
+                    incoming_label
+                };
+                self.graph.add_edge(*source, *target, label.clone());
+            }
+        }
+        self.graph.remove_node(merge_node);
+    }
+
+    // Tuple patterns are only destructured against tuple expressions of the same arity,
+    // otherwise the variables they bind are left unconstrained
+    fn check_destructuring(&self, pat: &syn::Pat, init: &Expr) {
+        match (pat, init) {
+            (syn::Pat::Type(pat_type), _) => self.check_destructuring(&pat_type.pat, init),
+            (_, Expr::Paren(paren)) => self.check_destructuring(pat, &paren.expr),
+            (syn::Pat::Tuple(pat_tuple), Expr::Tuple(expr_tuple)) => {
+                if pat_tuple.elems.len() != expr_tuple.elems.len() {
+                    log::warn!(
+                        "tuple pattern '{}' has {} elements but '{}' has {}, its bindings are ignored",
+                        quote!(#pat_tuple),
+                        pat_tuple.elems.len(),
+                        quote!(#expr_tuple),
+                        expr_tuple.elems.len()
+                    );
+                    return;
+                }
+                for (elem_pat, elem_expr) in pat_tuple.elems.iter().zip(expr_tuple.elems.iter()) {
+                    self.check_destructuring(elem_pat, elem_expr);
+                }
+            }
+            (syn::Pat::Tuple(pat_tuple), _) => log::warn!(
+                "tuple pattern '{}' can only be destructured from a tuple expression, '{}' is ignored",
+                quote!(#pat_tuple),
+                quote!(#init)
+            ),
+            _ => {}
+        }
+    }
+
+    pub fn format_macro_args(&self, tokens: &proc_macro2::TokenStream) -> String {
+        let tokens_str = tokens.to_string();
+        tokens_str
+            .trim_start_matches("!(")
+            .trim_end_matches(')')
+            .trim_matches(|c| c == '"' || c == '\'')
+            .to_string()
+    }
+}
+
+// Tokens all located at 'span'
+fn respanned(tokens: TokenStream, span: Span) -> TokenStream {
+    tokens
+        .into_iter()
+        .map(|mut token| {
+            if let TokenTree::Group(group) = &token {
+                let stream = respanned(group.stream(), span);
+                token = TokenTree::Group(Group::new(group.delimiter(), stream));
+            }
+            token.set_span(span);
+            token
+        })
+        .collect()
+}
+
+// Macros that make a function verified
+const ANNOTATION_MACROS: [&str; 4] = ["pre", "post", "invariant", "build_cfg"];
+
+// Names of the file's functions with annotation macros, in source order, e.g. to list them
+// before verifying. These are the functions 'build_cfg' builds the CFG of.
+pub fn annotated_functions(ast: &SynFile) -> Vec<String> {
+    let mut functions = AnnotatedFunctions::default();
+    functions.visit_file(ast);
+    functions.0
+}
+
+fn is_annotated(item_fn: &ItemFn) -> bool {
+    item_fn.block.stmts.iter().any(|stmt| match stmt {
+        Stmt::Semi(Expr::Macro(expr_macro), _) => expr_macro
+            .mac
+            .path
+            .get_ident()
+            .is_some_and(|ident| ANNOTATION_MACROS.iter().any(|name| ident == name)),
+        _ => false,
+    })
+}
+
+#[derive(Default)]
+struct AnnotatedFunctions(Vec<String>);
+
+impl Visit<'_> for AnnotatedFunctions {
+    // Functions nested in a function body aren't verified, so they aren't visited
+    fn visit_item_fn(&mut self, item_fn: &ItemFn) {
+        if is_annotated(item_fn) {
+            self.0.push(item_fn.sig.ident.to_string());
+        }
+    }
+}
+
+impl Visit<'_> for CfgBuilder {
+    // Process Rust source file.
+    fn visit_file(&mut self, i: &SynFile) {
+        visit::visit_file(self, i);
+    }
+
+    // Handle function definitions and statements
+    fn visit_item_fn(&mut self, i: &ItemFn) {
+        let func_name = i.sig.ident.to_string();
+
+        // Skip this function if no relevant macros are found
+        if !is_annotated(i) {
+            return;
+        }
+
+        // Skip annotated functions other than the requested one
+        self.annotated_functions.push(func_name.clone());
+        if let Some(filter) = &self.function_filter {
+            if *filter != func_name {
+                return;
+            }
+        }
+
+        // Lines of the function, to tell which function a path belongs to
+        let span = i.span();
+        self.function_lines
+            .push((func_name.clone(), span.start().line, span.end().line));
+
+        // Shadowing bindings get versioned names so they can't be confused in the WP calculus
+        let renamed_fn = rename_shadowed_bindings(&self.with_extra_postconditions(i));
+        let i = &renamed_fn;
+        self.bool_vars.insert(func_name.clone(), bool_bindings(i));
+        self.real_vars.insert(func_name.clone(), real_bindings(i));
+        self.returned_binding = returned_binding(&i.block);
+        self.return_nodes.clear();
+
+        let func_node = self.add_node(CfgNode::new_function(func_name.clone(), i.clone()));
+
+        self.current_node = Some(func_node);
+
+        // Process each statement in function body
+        for stmt in &i.block.stmts {
+            match stmt {
+                Stmt::Semi(expr, _) => {
+                    // Statement usually ending with semicolumn
+                    // Handle macro expressions
+                    if let Expr::Macro(expr_macro) = expr {
+                        if let Some(macro_ident) = expr_macro.mac.path.get_ident() {
+                            let macro_name = macro_ident.to_string();
+                            if macro_name.as_str() == "build_cfg" {
+                                continue; // Skip processing this macro
+                            }
+                            let macro_args = self.format_macro_args(&expr_macro.mac.tokens);
+                            // handle annotation macros
+                            let node = match macro_name.as_str() {
+                                "pre" => CfgNode::new_precondition(
+                                    macro_args.clone(),
+                                    Expr::Macro(expr_macro.clone()),
+                                ),
+                                "post" => {
+                                    let post_node = CfgNode::new_postcondition(
+                                        macro_args.clone(),
+                                        Expr::Macro(expr_macro.clone()),
+                                    );
+                                    // add postconditions to vec to later merge them at the end of the CFG.
+                                    self.postconditions.push(post_node.clone());
+                                    post_node
+                                }
+                                "invariant" => CfgNode::new_invariant(
+                                    macro_args.clone(),
+                                    Expr::Macro(expr_macro.clone()),
+                                ),
+                                "assert" => CfgNode::new_assertion(
+                                    macro_args.clone(),
+                                    Expr::Macro(expr_macro.clone()),
+                                ),
+                                "assume" => CfgNode::new_assumption(
+                                    macro_args.clone(),
+                                    Expr::Macro(expr_macro.clone()),
+                                ),
+                                _ => {
+                                    let expr_str = quote!(#i).to_string();
+                                    CfgNode::new_statement(
+                                        expr_str,
+                                        Stmt::Expr(Expr::Macro(expr_macro.clone())),
+                                    )
+                                }
+                            };
+                            if macro_name.as_str() != "post" {
+                                self.add_node(node);
+                            }
+                        } else {
+                            self.visit_expr(expr);
+                        }
+                    } else {
+                        self.visit_expr(expr);
+                    }
+                }
+                Stmt::Expr(expr) => self.handle_tail_expression(expr),
+                _ => self.visit_stmt(stmt),
+            }
+        }
+        self.connect_returns();
+        self.add_postconditions();
+
+        self.current_node = None;
+    }
+
+    // Processes Rust expressions (loops, conditions, macros, etc.)
+    fn visit_expr(&mut self, i: &Expr) {
+        match i {
+            Expr::If(expr_if) => self.handle_if_statement(expr_if),
+            Expr::While(expr_while) => self.handle_while_loop(expr_while),
+            Expr::ForLoop(expr_for) => self.handle_for_loop(expr_for),
+            Expr::Loop(expr_loop) => self.handle_loop(expr_loop),
+            Expr::Match(expr_match) => self.handle_match(expr_match),
+            Expr::Break(expr_break) => self.handle_break(expr_break),
+            Expr::Continue(expr_continue) => self.handle_continue(expr_continue),
+            Expr::Return(expr_return) => {
+                self.handle_return_statement(expr_return);
+            }
+            Expr::Call(expr_call) => self.handle_call(expr_call),
+            Expr::MethodCall(expr_method_call) => self.handle_method_call(expr_method_call),
+            Expr::Macro(expr_macro) => {
+                self.process_macro(expr_macro); // method from the handle_macro module
+            }
+            Expr::Array(expr_array) => {
+                for elem in &expr_array.elems {
+                    self.visit_expr(elem); // Recursively visit to catch nested macros
+                }
+            }
+            _ => {
+                // Handling invariant macro
+                if let Expr::Macro(expr_macro) = i {
+                    if let Some(macro_ident) = expr_macro.mac.path.get_ident() {
+                        if macro_ident == "invariant" {
+                            // Handling invariant
+                            let invariant_str = self.format_macro_args(&expr_macro.mac.tokens);
+                            self.add_node(CfgNode::new_invariant(
+                                invariant_str,
+                                Expr::Macro(expr_macro.clone()),
+                            ));
+                            return;
+                        }
+                    }
+                }
+                // else a simple expression.
+                self.add_call_contracts_in(i);
+                let mut expr = i.clone();
+                self.desugar_try_operators(&mut expr);
+                let i = &expr;
+                let expr_str = quote!(#i).to_string();
+                let call_statement = Stmt::Expr(i.clone());
+                self.add_node(CfgNode::new_statement(expr_str, call_statement));
+            }
+        }
+    }
+    // Method to visit code blocks
+    fn visit_block(&mut self, i: &Block) {
+        for stmt in &i.stmts {
+            self.visit_stmt(stmt);
+        }
+    }
+    fn visit_stmt(&mut self, i: &Stmt) {
+        match i {
+            Stmt::Local(local) => {
+                // Handle local variable declarations
+                if let Some((_, init)) = &local.init {
+                    self.check_destructuring(&local.pat, init);
+                }
+                self.add_call_contracts_in_local(local);
+                let mut local = local.clone();
+                if let Some((_, init)) = &mut local.init {
+                    self.desugar_try_operators(init);
+                }
+                let local = &local;
+                let local_str = format!("{}", quote!(#local));
+                self.add_node(CfgNode::new_statement(
+                    local_str,
+                    Stmt::Local(local.clone()),
+                ));
+            }
+            Stmt::Expr(expr) | Stmt::Semi(expr, _) => self.visit_expr(expr),
+            _ => visit::visit_stmt(self, i),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CfgBuilder, ExternalMethod};
+    use crate::{run_verification_str, VerificationOptions, VerifyOutcome};
+
+    #[test]
+    fn verification_conditions_are_listed_in_path_order() {
+        let conditions = CfgBuilder::verification_conditions(
+            "fn f(x: i32) -> i32 { pre!(x > 0); let mut y = x; if y > 5 { y = 5; } \
+             post!(y > 0); y }",
+        )
+        .unwrap();
+        assert_eq!(
+            conditions,
+            [
+                "pre ! (x > 0) >> ! (x > 5) >> post ! (x > 0)",
+                "pre ! (x > 0) >> (x > 5) >> post ! (5 > 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_branches_are_separate_paths() {
+        let conditions = CfgBuilder::verification_conditions(
+            "fn f(x: i32) -> i32 { pre!(true); if x < 0 { } post!(x < 0 || x >= 0); x }",
+        )
+        .unwrap();
+        assert_eq!(
+            conditions,
+            [
+                "pre ! (true) >> (x < 0) >> post ! (x < 0 || x >= 0)",
+                "pre ! (true) >> ! (x < 0) >> post ! (x < 0 || x >= 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn each_postcondition_is_a_separate_goal() {
+        let source = "fn f(x: i32) -> i32 { pre!(x > 0); let y = x + 1; \
+            post!(y > 1); post!(y > 5); y }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (x > 0) >> post ! ((x + 1) > 1)",
+                "pre ! (x > 0) >> post ! ((x + 1) > 5)",
+            ]
+        );
+        let report = run_verification_str(source, &VerificationOptions::default()).unwrap();
+        assert!(matches!(report.paths[0].outcome, VerifyOutcome::Valid));
+        assert!(report.paths[1].outcome.is_invalid());
+    }
+
+    #[test]
+    fn verification_conditions_report_syntax_errors() {
+        assert!(CfgBuilder::verification_conditions("fn f( {").is_err());
+    }
+
+    #[test]
+    fn merge_points_of_nested_conditionals_are_removed() {
+        let ast = syn::parse_file(
+            "fn f(x: i32) -> i32 { pre!(true); let mut y = 0; \
+             if x > 0 { if x > 10 { y = 2; } else { y = 1; } } else { y = 3; } \
+             post!(y > 0); y }",
+        )
+        .unwrap();
+        let mut builder = CfgBuilder::with_default_config().unwrap();
+        builder.build_cfg(&ast);
+        assert!(!builder.dump().contains("MergePoint"));
+        let basic_paths = builder.generate_basic_paths();
+        assert_eq!(
+            builder.apply_wp_calculus(&basic_paths),
+            [
+                "pre ! (true) >> ! (x > 0) >> post ! (3 > 0)",
+                "pre ! (true) >> (x > 0) >> ! (x > 10) >> post ! (1 > 0)",
+                "pre ! (true) >> (x > 0) >> (x > 10) >> post ! (2 > 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn external_methods_are_registered_in_code() {
+        let ast = syn::parse_file(
+            "fn f(mut v: Vec<i32>) { pre!(v.len() < 10); v.push(1); post!(true); }",
+        )
+        .unwrap();
+        let mut builder = CfgBuilder::with_external_methods(Vec::new());
+        builder.add_external_method(ExternalMethod {
+            name: "push".to_string(),
+            preconditions: vec!["v.len() < 50".to_string()],
+            postconditions: vec!["v.len() > 0".to_string()],
+        });
+        // Replaces the contract registered first
+        builder.add_external_method(ExternalMethod {
+            name: "push".to_string(),
+            preconditions: vec!["v.len() < 100".to_string()],
+            postconditions: vec!["v.len() > 0".to_string()],
+        });
+        builder.build_cfg(&ast);
+        let basic_paths = builder.generate_basic_paths();
+        assert_eq!(
+            builder.apply_wp_calculus(&basic_paths),
+            [
+                "pre ! (v . len () < 10) >> assert ! (v . len () < 100)",
+                "pre ! (v . len () < 10) >> assert ! (v . len () < 100) >> \
+                 assume ! (v . len () > 0) >> post ! (true)",
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_and_invalid_conditions_files_are_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("secrust-conditions-{}.json", std::process::id()));
+        let error_for = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            CfgBuilder::parse_external_definitions(&path)
+                .unwrap_err()
+                .to_string()
+        };
+        // serde_json tells where the syntax error is
+        let error = error_for("{ \"external_methods\": [ { \"name\": \"len\", }\n] }");
+        assert!(error.contains("line 1 column"), "{}", error);
+        let error = error_for(
+            r#"{ "external_methods": [ { "name": " ", "preconditions": [], "postconditions": [] } ] }"#,
+        );
+        assert!(
+            error.ends_with("external method 1 has an empty name"),
+            "{}",
+            error
+        );
+        let error = error_for(
+            r#"{ "external_methods": [ { "name": "pop", "preconditions": ["len >"], "postconditions": [] } ] }"#,
+        );
+        assert!(
+            error.contains("precondition 'len >' of 'pop' is not a valid expression"),
+            "{}",
+            error
+        );
+        std::fs::write(
+            &path,
+            r#"{ "external_methods": [ { "name": "pop", "preconditions": ["len > 0"], "postconditions": [] } ] }"#,
+        )
+        .unwrap();
+        let methods = CfgBuilder::parse_external_definitions(&path).unwrap();
+        assert_eq!(methods.external_methods[0].name, "pop");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn external_contracts_are_checked_and_assumed() {
+        let options = VerificationOptions {
+            external_methods: vec![ExternalMethod {
+                name: "push".to_string(),
+                preconditions: vec!["v.len() < 5".to_string()],
+                postconditions: vec!["v.len() > 0".to_string()],
+            }],
+            ..VerificationOptions::default()
+        };
+        let report = run_verification_str(
+            "fn f(mut v: Vec<i32>) { pre!(v.len() < 10); v.push(1); post!(v.len() > 0); }",
+            &options,
+        )
+        .unwrap();
+        assert!(matches!(
+            [&report.paths[0].outcome, &report.paths[1].outcome],
+            [VerifyOutcome::Invalid(_), VerifyOutcome::Valid]
+        ));
+    }
+}
@@ -0,0 +1,79 @@
+use quote::quote;
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::visit_mut::{self, VisitMut};
+use syn::{Expr, File as SynFile, Macro, Token};
+
+// Annotations whose arguments are all conditions. 'assert!' is left out, its second argument
+// is a message.
+const CONJUNCT_MACROS: [&str; 4] = ["pre", "post", "invariant", "assume"];
+
+// The file with the comma-separated conditions of its annotations joined into one, e.g.
+// 'pre!(x > 0, y > 0)' as 'pre!((x > 0) && (y > 0))', so everything else sees one condition
+pub fn join_conjuncts(ast: &SynFile) -> SynFile {
+    let mut ast = ast.clone();
+    JoinConjuncts.visit_file_mut(&mut ast);
+    ast
+}
+
+struct JoinConjuncts;
+
+impl VisitMut for JoinConjuncts {
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        let is_conjunct_macro = mac
+            .path
+            .get_ident()
+            .is_some_and(|ident| CONJUNCT_MACROS.iter().any(|name| ident == name));
+        if is_conjunct_macro {
+            let parser = Punctuated::<Expr, Token![,]>::parse_terminated;
+            // Arguments that aren't conditions are left for the verifier to report
+            if let Ok(conditions) = parser.parse2(mac.tokens.clone()) {
+                if conditions.len() > 1 || conditions.trailing_punct() {
+                    let conditions = conditions.iter();
+                    mac.tokens = quote!(#((#conditions))&&*);
+                }
+            }
+        }
+        visit_mut::visit_macro_mut(self, mac);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::join_conjuncts;
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+    use quote::quote;
+
+    #[test]
+    fn comma_separated_conditions_are_joined() {
+        let ast = syn::parse_file(
+            "fn f(x: i32, y: i32, z: i32) { pre!(x > 0, y > 0); pre!(x > 0, y > 0, z > 0,); \
+             post!(x > 0); assert!(x > 0, \"x is positive\"); }",
+        )
+        .unwrap();
+        let joined = join_conjuncts(&ast);
+        let expected: syn::File = syn::parse_quote! {
+            fn f(x: i32, y: i32, z: i32) {
+                pre!((x > 0) && (y > 0));
+                pre!((x > 0) && (y > 0) && (z > 0));
+                post!(x > 0);
+                assert!(x > 0, "x is positive");
+            }
+        };
+        assert_eq!(quote!(#joined).to_string(), quote!(#expected).to_string());
+    }
+
+    #[test]
+    fn every_conjunct_is_assumed_and_checked() {
+        let source = "fn f(x: i32, y: i32, z: i32) -> i32 { pre!(x > 0, y > 0, z > 0); \
+            let s = x + y + z; post!(s > 2, s > x); s }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            ["pre ! ((x > 0) && (y > 0) && (z > 0)) >> post ! (((x + y + z) > 2) && ((x + y + z) > x))"]
+        );
+        assert!(matches!(path_outcomes(source)[..], [VerifyOutcome::Valid]));
+        let two = "fn f(x: i32, y: i32) -> i32 { pre!(x > 0, y > 0); post!(x + y > 1); x }";
+        assert!(matches!(path_outcomes(two)[..], [VerifyOutcome::Valid]));
+    }
+}
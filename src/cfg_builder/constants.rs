@@ -0,0 +1,68 @@
+use crate::cfg_builder::builder::CfgBuilder;
+use syn::{Expr, File as SynFile, Item, Type};
+
+// Integer and boolean types a constant can be translated with
+const CONSTANT_TYPES: [&str; 13] = [
+    "bool", "i8", "i16", "i32", "i64", "i128", "isize", "u8", "u16", "u32", "u64", "u128", "usize",
+];
+
+impl CfgBuilder {
+    // Values of the file's integer and boolean 'const' and (immutable) 'static' items, by name.
+    // A value may use the constants declared before it, which are substituted into it.
+    pub fn collect_constants(&self, ast: &SynFile) -> Vec<(String, Expr)> {
+        let mut constants: Vec<(String, Expr)> = Vec::new();
+        for item in &ast.items {
+            let (ident, ty, value) = match item {
+                Item::Const(item_const) => (&item_const.ident, &item_const.ty, &item_const.expr),
+                Item::Static(item_static) if item_static.mutability.is_none() => {
+                    (&item_static.ident, &item_static.ty, &item_static.expr)
+                }
+                _ => continue,
+            };
+            let Type::Path(type_path) = &**ty else {
+                continue;
+            };
+            if !CONSTANT_TYPES
+                .iter()
+                .any(|name| type_path.path.is_ident(name))
+            {
+                continue;
+            }
+            let value = self.substitute_constants(value, &constants);
+            constants.push((ident.to_string(), value));
+        }
+        constants
+    }
+
+    // Replaces the constants in an expression with their values. The values don't mention
+    // other constants, so they can be substituted one after the other.
+    pub fn substitute_constants(&self, expr: &Expr, constants: &[(String, Expr)]) -> Expr {
+        constants.iter().fold(expr.clone(), |expr, (name, value)| {
+            self.recursive_substitution(&expr, name, value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    #[test]
+    fn constants_are_replaced_by_their_values() {
+        let source = "const LIMIT: i32 = 100;
+static HALF: i32 = LIMIT / 2;
+fn f(n: i32) -> i32 { pre!(n >= 0); let mut x = n; if x > LIMIT { x = LIMIT; } \
+            post!(x <= LIMIT && HALF * 2 == LIMIT); x }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (n >= 0) >> ! (n > 100) >> post ! (n <= 100 && (100 / 2) * 2 == 100)",
+                "pre ! (n >= 0) >> (n > 100) >> post ! (100 <= 100 && (100 / 2) * 2 == 100)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+}
@@ -0,0 +1,341 @@
+use crate::cfg_builder::builder::CfgBuilder;
+use crate::cfg_builder::handle_match::is_binding;
+use crate::cfg_builder::handle_return::returned_binding;
+use crate::cfg_builder::node::CfgNode;
+use crate::cfg_builder::ssa::rename_shadowed_bindings;
+use quote::{quote, quote_spanned};
+use std::collections::{HashMap, HashSet};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::{Expr, ExprCall, ExprClosure, File as SynFile, FnArg, Item, ItemFn, Local, Pat, Stmt};
+
+// Pre- and postconditions of a function defined in the verified file
+#[derive(Debug, Clone)]
+pub struct FunctionContract {
+    pub params: Vec<String>,
+    pub preconditions: Vec<Expr>,
+    pub postconditions: Vec<Expr>,
+    pub result: Option<String>, // variable the function returns at its end, if any
+    pub locals: Vec<String>,    // variables bound or assigned in the body
+}
+
+impl FunctionContract {
+    fn from_item_fn(item_fn: &ItemFn) -> Option<Self> {
+        // Use the same versioned names as the CFG of the function itself
+        let item_fn = rename_shadowed_bindings(item_fn);
+
+        let mut preconditions = Vec::new();
+        let mut postconditions = Vec::new();
+        for stmt in &item_fn.block.stmts {
+            if let Stmt::Semi(Expr::Macro(expr_macro), _) = stmt {
+                let annotations = if expr_macro.mac.path.is_ident("pre") {
+                    &mut preconditions
+                } else if expr_macro.mac.path.is_ident("post") {
+                    &mut postconditions
+                } else {
+                    continue;
+                };
+                if let Ok(condition) = syn::parse2::<Expr>(expr_macro.mac.tokens.clone()) {
+                    annotations.push(condition);
+                }
+            }
+        }
+        if preconditions.is_empty() && postconditions.is_empty() {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        for input in &item_fn.sig.inputs {
+            if let FnArg::Typed(pat_type) = input {
+                match &*pat_type.pat {
+                    Pat::Ident(pat_ident) => params.push(pat_ident.ident.to_string()),
+                    // Arguments can't be matched to destructuring parameters
+                    _ => return None,
+                }
+            }
+        }
+
+        let result = returned_binding(&item_fn.block);
+
+        let mut bindings = LocalBindings::default();
+        bindings.visit_block(&item_fn.block);
+        let mut locals = bindings.0;
+        locals.sort();
+        locals.dedup();
+        Some(FunctionContract {
+            params,
+            preconditions,
+            postconditions,
+            result,
+            locals,
+        })
+    }
+}
+
+// Variables bound by 'let', 'for' and match arm patterns, and variables assigned to
+#[derive(Default)]
+struct LocalBindings(Vec<String>);
+
+impl LocalBindings {
+    fn assigned(&mut self, left: &Expr) {
+        match left {
+            Expr::Path(expr_path) => {
+                if let Some(ident) = expr_path.path.get_ident() {
+                    self.0.push(ident.to_string());
+                }
+            }
+            Expr::Index(expr_index) => self.assigned(&expr_index.expr),
+            _ => {}
+        }
+    }
+}
+
+impl Visit<'_> for LocalBindings {
+    fn visit_expr_assign(&mut self, assign: &syn::ExprAssign) {
+        self.assigned(&assign.left);
+        visit::visit_expr_assign(self, assign);
+    }
+
+    fn visit_expr_assign_op(&mut self, assign_op: &syn::ExprAssignOp) {
+        self.assigned(&assign_op.left);
+        visit::visit_expr_assign_op(self, assign_op);
+    }
+
+    fn visit_pat_ident(&mut self, pat_ident: &syn::PatIdent) {
+        if is_binding(&pat_ident.ident) {
+            self.0.push(pat_ident.ident.to_string());
+        }
+        visit::visit_pat_ident(self, pat_ident);
+    }
+
+    fn visit_expr_closure(&mut self, _closure: &ExprClosure) {}
+
+    fn visit_item(&mut self, _item: &Item) {}
+}
+
+// Calls in an expression, innermost first
+#[derive(Default)]
+struct Calls(Vec<ExprCall>);
+
+impl Visit<'_> for Calls {
+    fn visit_expr_call(&mut self, expr_call: &ExprCall) {
+        visit::visit_expr_call(self, expr_call);
+        self.0.push(expr_call.clone());
+    }
+
+    fn visit_expr_closure(&mut self, _closure: &ExprClosure) {}
+}
+
+// Names of the functions each function of the file calls
+fn call_graph(ast: &SynFile) -> HashMap<String, HashSet<String>> {
+    ast.items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Fn(item_fn) => {
+                let mut calls = Calls::default();
+                calls.visit_block(&item_fn.block);
+                let callees = calls
+                    .0
+                    .iter()
+                    .filter_map(|call| match &*call.func {
+                        Expr::Path(func) => func.path.get_ident().map(|ident| ident.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                Some((item_fn.sig.ident.to_string(), callees))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Whether 'name' can reach itself in the call graph
+fn is_recursive(name: &str, call_graph: &HashMap<String, HashSet<String>>) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack: Vec<&String> = call_graph.get(name).into_iter().flatten().collect();
+    while let Some(callee) = stack.pop() {
+        if callee == name {
+            return true;
+        }
+        if visited.insert(callee) {
+            stack.extend(call_graph.get(callee).into_iter().flatten());
+        }
+    }
+    false
+}
+
+impl CfgBuilder {
+    // Contracts of all annotated functions in the file, by name
+    pub fn collect_contracts(ast: &SynFile) -> HashMap<String, FunctionContract> {
+        let call_graph = call_graph(ast);
+        ast.items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Fn(item_fn) => FunctionContract::from_item_fn(item_fn).map(|contract| {
+                    let name = item_fn.sig.ident.to_string();
+                    // Calls are never expanded, so a recursive call only uses the contract:
+                    // its precondition is checked and its postcondition assumed, like any call.
+                    // This proves partial correctness, termination isn't checked.
+                    if is_recursive(&name, &call_graph) {
+                        log::info!(
+                            "'{}' is recursive: its recursive calls are verified against its contract, termination isn't checked",
+                            name
+                        );
+                    }
+                    (name, contract)
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Contracts of the calls in a statement's expressions, added before the statement itself
+    pub fn add_call_contracts_in_local(&mut self, local: &Local) {
+        if let Some((_, init)) = &local.init {
+            self.add_call_contracts_in(init);
+        }
+    }
+
+    pub fn add_call_contracts_in(&mut self, expr: &Expr) {
+        let mut calls = Calls::default();
+        calls.visit_expr(expr);
+        for call in calls.0 {
+            self.add_call_contract(&call);
+        }
+    }
+
+    // At a call of a function with a known contract, its precondition (with the arguments
+    // for the parameters) has to hold, and its postcondition holds for the result afterwards.
+    // The result itself is the uninterpreted function call, so equal arguments give equal results.
+    pub fn add_call_contract(&mut self, call: &ExprCall) {
+        let Expr::Path(func) = &*call.func else {
+            return;
+        };
+        let Some(name) = func.path.get_ident().map(|ident| ident.to_string()) else {
+            return;
+        };
+        let Some(contract) = self.contracts.get(&name).cloned() else {
+            return;
+        };
+        if contract.params.len() != call.args.len() {
+            return;
+        }
+
+        let call_expr = Expr::Call(call.clone());
+        let span = call.span();
+        let args: Vec<Expr> = call.args.iter().cloned().collect();
+        let call_str = Self::clean_up_formatting(&quote!(#call).to_string());
+
+        let entry_values: Vec<(String, Expr)> = contract
+            .params
+            .iter()
+            .cloned()
+            .zip(args.iter().cloned())
+            .collect();
+
+        for pre in &contract.preconditions {
+            let cond = self.simultaneous_substitution(pre, &entry_values);
+            let label = Self::clean_up_formatting(&quote!(#cond).to_string());
+            let check = syn::parse2(quote_spanned!(span=> assert!(#cond)))
+                .expect("Failed to build call precondition");
+            self.add_node(CfgNode::new_call_precondition(
+                format!("{}: {}", call_str, label),
+                check,
+            ));
+        }
+
+        // In the postcondition, 'old!(p)' and the parameters the callee doesn't assign are the
+        // arguments, and the returned variable is the call. Other callee variables say nothing
+        // about the caller's, so they become unconstrained '__<function>_<var>' constants.
+        let mut exit_values = Vec::new();
+        for (param, arg) in &entry_values {
+            exit_values.push((format!("__old_{}", param), arg.clone()));
+            if !contract.locals.contains(param) && contract.result.as_ref() != Some(param) {
+                exit_values.push((param.clone(), arg.clone()));
+            }
+        }
+        for local in &contract.locals {
+            if contract.result.as_ref() != Some(local) {
+                let hidden = syn::Ident::new(&format!("__{}_{}", name, local), span);
+                exit_values.push((local.clone(), syn::parse_quote!(#hidden)));
+            }
+        }
+        if let Some(result) = &contract.result {
+            exit_values.push((result.clone(), call_expr.clone()));
+        }
+
+        for post in &contract.postconditions {
+            let post = self.resolve_old_values(post, false);
+            let cond = self.simultaneous_substitution(&post, &exit_values);
+            let label = Self::clean_up_formatting(&quote!(#cond).to_string());
+            let assumption = syn::parse2(quote_spanned!(span=> assume!(#cond)))
+                .expect("Failed to build call postcondition");
+            self.add_node(CfgNode::new_assumption(
+                format!("{}: {}", call_str, label),
+                assumption,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    const CALLEE: &str =
+        "fn inc(x: i32) -> i32 { pre!(x >= 0); let r = x + 1; post!(result > x); r }\n";
+
+    #[test]
+    fn callee_contracts_are_asserted_and_assumed() {
+        let source = format!(
+            "{}fn f(a: i32) -> i32 {{ pre!(a >= 0); let b = inc(a); post!(b > a); b }}",
+            CALLEE
+        );
+        assert_eq!(
+            CfgBuilder::verification_conditions(&source).unwrap(),
+            [
+                "pre ! (x >= 0) >> post ! ((x + 1) > x)",
+                "pre ! (a >= 0) >> assert ! (a >= 0)",
+                "pre ! (a >= 0) >> assert ! (a >= 0) >> assume ! ((inc (a)) > a) >> post ! ((inc (a)) > a)",
+            ]
+        );
+        assert!(path_outcomes(&source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn calls_must_satisfy_the_callee_precondition() {
+        let outcomes = path_outcomes(&format!(
+            "{}fn g(a: i32) -> i32 {{ pre!(true); let b = inc(a); post!(b > a); b }}",
+            CALLEE
+        ));
+        assert!(matches!(
+            outcomes[..],
+            [
+                VerifyOutcome::Valid,
+                VerifyOutcome::Invalid(_),
+                VerifyOutcome::Valid
+            ]
+        ));
+    }
+
+    #[test]
+    fn recursive_calls_use_the_contract_of_the_function() {
+        let source = "fn fact(n: i32) -> i32 { pre!(n >= 0); let mut r = 1; \
+                      if n > 0 { r = n * fact(n - 1); } post!(result >= 1); r }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (n >= 0) >> ! (n > 0) >> post ! (1 >= 1)",
+                "pre ! (n >= 0) >> (n > 0) >> assert ! ((n - 1) >= 0)",
+                "pre ! (n >= 0) >> (n > 0) >> assert ! ((n - 1) >= 0) >> \
+                 assume ! ((fact (n - 1)) >= 1) >> post ! ((n * fact (n - 1)) >= 1)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+}
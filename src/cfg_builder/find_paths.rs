@@ -1,8 +1,10 @@
 use crate::cfg_builder::{builder::CfgBuilder, node::CfgNode};
+use crate::verifier::{PathKind, PathSource, PathTarget, VerifyOutcome};
 use petgraph::graph::NodeIndex;
 use petgraph::visit::EdgeRef;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::Path;
 
 impl CfgBuilder {
@@ -33,6 +35,7 @@ impl CfgBuilder {
                     CfgNode::Precondition(_, _)
                         | CfgNode::Postcondition(_, _)
                         | CfgNode::Invariant(_, _)
+                        | CfgNode::Assertion(_, _)
                         | CfgNode::Cutoff(_)
                 )
             })
@@ -54,17 +57,23 @@ impl CfgBuilder {
             .map(|edge| (edge.target(), edge.weight().clone()))
             .collect();
 
-        // Check for a terminal condition or another condition node
+        // Check for a terminal condition or another condition node.
+        // Assertions are cut points: paths end there and new ones start from them.
         if matches!(
             self.graph[current_node],
             CfgNode::Precondition(_, _)
                 | CfgNode::Postcondition(_, _)
                 | CfgNode::Invariant(_, _)
+                | CfgNode::Assertion(_, _)
                 | CfgNode::Cutoff(_)
         ) && current_path.len() > 1
         {
             paths.push(current_path.clone());
         } else {
+            // A callee precondition is checked by the path ending at it, and the path goes on
+            if matches!(self.graph[current_node], CfgNode::CallPrecondition(_, _)) {
+                paths.push(current_path.clone());
+            }
             // Continue exploring adjacent nodes
             for (target, _edge_label) in edges_info {
                 self.find_paths(target, current_path, paths);
@@ -87,9 +96,11 @@ impl CfgBuilder {
         })
     }
 
+    // A loop path ends at the invariant it loops back to, its own or, leaving a nested loop,
+    // the one of the outer loop
     fn process_loop_invariant_path(&mut self, path: &mut Vec<NodeIndex>) {
-        if let Some(&first_node) = path.first() {
-            if let CfgNode::Invariant(cond, expr) = &self.graph[first_node] {
+        if let Some(&last_node) = path.last() {
+            if let CfgNode::Invariant(cond, expr) = &self.graph[last_node] {
                 // Create a new terminal node with the same invariant condition
                 let new_terminal_node = self
                     .graph
@@ -104,17 +115,80 @@ impl CfgBuilder {
         }
     }
 
-    pub fn write_paths_to_dot_files(&self, paths: Vec<Vec<NodeIndex>>, base_path: &Path) {
+    // Annotation at the end of a basic path, which the path has to establish
+    pub fn path_target(&self, path: &[NodeIndex]) -> Option<PathTarget> {
+        let last_node = &self.graph[*path.last()?];
+        let kind = last_node.annotation_kind()?;
+        Some(PathTarget {
+            kind: kind.to_string(),
+            location: last_node.location(),
+        })
+    }
+
+    // Kind of obligation the path stands for, from the annotations at both of its ends
+    pub fn path_kind(&self, path: &[NodeIndex]) -> Option<PathKind> {
+        let start = self.graph[*path.first()?].annotation_kind()?;
+        let end = self.graph[*path.last()?].annotation_kind()?;
+        Some(PathKind::from_annotation_kinds(start, end))
+    }
+
+    // Function of the path and the lines between its first and last node with a location
+    pub fn path_source(&self, path: &[NodeIndex]) -> Option<PathSource> {
+        let lines = path
+            .iter()
+            .filter_map(|&node| self.graph[node].location())
+            .map(|location| location.line);
+        let start_line = lines.clone().min()?;
+        let end_line = lines.max()?;
+        let (function, _, _) = self
+            .function_lines
+            .iter()
+            .find(|(_, first, last)| (*first..=*last).contains(&start_line))?;
+        Some(PathSource {
+            function: function.clone(),
+            start_line,
+            end_line,
+        })
+    }
+
+    pub fn write_paths_to_dot_files(
+        &self,
+        paths: Vec<Vec<NodeIndex>>,
+        base_path: &Path,
+    ) -> io::Result<()> {
+        let outcomes = vec![None; paths.len()];
+        self.write_annotated_paths_to_dot_files(paths, &outcomes, false, base_path)
+    }
+
+    // Like 'write_paths_to_dot_files', with each path colored by its verification outcome,
+    // 'outcomes[i]' being the outcome of 'paths[i]' (None for paths without a condition).
+    // With 'with_wp', nodes also show the weakest precondition of the rest of the path.
+    pub fn write_annotated_paths_to_dot_files(
+        &self,
+        paths: Vec<Vec<NodeIndex>>,
+        outcomes: &[Option<VerifyOutcome>],
+        with_wp: bool,
+        base_path: &Path,
+    ) -> io::Result<()> {
         // Create the output directory if it doesn't exist
-        std::fs::create_dir_all(base_path).expect("Unable to create base directory for paths");
+        std::fs::create_dir_all(base_path)?;
 
         for (i, path) in paths.iter().enumerate() {
             let mut dot_string = String::from("digraph Path {\n");
+            let color = outcomes
+                .get(i)
+                .and_then(|outcome| outcome.as_ref().map(outcome_color));
+            let wp_conditions = if with_wp {
+                self.wp_conditions(path)
+            } else {
+                HashMap::new()
+            };
 
             // Add nodes to the DOT string
             for &node in path {
                 let cfg_node = &self.graph[node];
-                dot_string.push_str(&cfg_node.format_dot(node.index()));
+                let wp = wp_conditions.get(&node).map(String::as_str);
+                dot_string.push_str(&cfg_node.format_dot_annotated(node.index(), color, wp));
                 dot_string.push('\n');
             }
 
@@ -124,16 +198,24 @@ impl CfgBuilder {
                     // Find all edges connecting 'from' to 'to'
                     let edges: Vec<_> = self.graph.edges_connecting(*from, *to).collect();
 
-                    if let Some(edge) = edges.first() {
-                        let label = &self.graph[edge.id()];
+                    let mut attributes: Vec<String> = edges
+                        .first()
+                        .map(|edge| format!("label=\"{}\"", self.graph[edge.id()]))
+                        .into_iter()
+                        .collect();
+                    if let Some(color) = color {
+                        attributes.push(format!("color={}", color));
+                    }
+
+                    if attributes.is_empty() {
+                        dot_string.push_str(&format!("{} -> {};\n", from.index(), to.index()));
+                    } else {
                         dot_string.push_str(&format!(
-                            "{} -> {} [label=\"{}\"];\n",
+                            "{} -> {} [{}];\n",
                             from.index(),
                             to.index(),
-                            label
+                            attributes.join(", ")
                         ));
-                    } else {
-                        dot_string.push_str(&format!("{} -> {};\n", from.index(), to.index()));
                     }
                 }
             }
@@ -141,11 +223,137 @@ impl CfgBuilder {
             dot_string.push_str("}\n");
 
             // Write the DOT file
-            let dot_file_path = base_path.join(format!("basic_path_{}.dot", i));
-            let mut dot_file = File::create(&dot_file_path).expect("Unable to create DOT file");
-            dot_file
-                .write_all(dot_string.as_bytes())
-                .expect("Unable to write to DOT file");
+            let dot_file_path = base_path.join(format!("basic_path_{}.dot", i + 1));
+            let mut dot_file = File::create(&dot_file_path)?;
+            dot_file.write_all(dot_string.as_bytes())?;
         }
+        Ok(())
+    }
+
+    // Whole CFG in one DOT graph, with the edges of each basic path tinted in a color of its own.
+    // An edge shared by several paths is drawn as one parallel line per path, and its label
+    // lists the paths through it, numbered from 1 like the 'basic_path_N.dot' files and the report.
+    // Edges on no path are dashed.
+    pub fn to_dot_with_paths(&self, paths: &[Vec<NodeIndex>]) -> String {
+        let mut edge_paths: HashMap<(NodeIndex, NodeIndex), Vec<usize>> = HashMap::new();
+        for (i, path) in paths.iter().enumerate() {
+            for window in path.windows(2) {
+                let (from, mut to) = (window[0], window[1]);
+                // Loop paths end at a copy of the invariant they loop back to, drawn as the invariant
+                if self.graph.find_edge(from, to).is_none() {
+                    to = self
+                        .graph
+                        .edges(from)
+                        .map(|edge| edge.target())
+                        .find(|&target| matches!(self.graph[target], CfgNode::Invariant(_, _)))
+                        .unwrap_or(path[0]);
+                }
+                let path_indices = edge_paths.entry((from, to)).or_default();
+                if path_indices.last() != Some(&i) {
+                    path_indices.push(i);
+                }
+            }
+        }
+
+        let mut dot_string = String::from("digraph G {\n");
+        self.push_dot_nodes(&mut dot_string);
+        for edge in self.graph.edge_references() {
+            let (source, target) = (edge.source(), edge.target());
+            let label = edge.weight();
+            match edge_paths.get(&(source, target)) {
+                Some(path_indices) => {
+                    let colors: Vec<&str> = path_indices
+                        .iter()
+                        .map(|&i| PATH_COLORS[i % PATH_COLORS.len()])
+                        .collect();
+                    let numbers: Vec<String> =
+                        path_indices.iter().map(|i| (i + 1).to_string()).collect();
+                    let paths_label = match numbers.as_slice() {
+                        [number] => format!("path {}", number),
+                        _ => format!("paths {}", numbers.join(", ")),
+                    };
+                    let label = if label.is_empty() {
+                        paths_label
+                    } else {
+                        format!("{} ({})", label, paths_label)
+                    };
+                    dot_string.push_str(&format!(
+                        "{} -> {} [label=\"{}\", color=\"{}\", penwidth=2];\n",
+                        source.index(),
+                        target.index(),
+                        label,
+                        colors.join(":")
+                    ));
+                }
+                None => dot_string.push_str(&format!(
+                    "{} -> {} [label=\"{}\", style=dashed];\n",
+                    source.index(),
+                    target.index(),
+                    label
+                )),
+            }
+        }
+        dot_string.push_str("}\n");
+        dot_string
+    }
+}
+
+// Colors of the paths in 'to_dot_with_paths', reused when there are more paths
+const PATH_COLORS: [&str; 8] = [
+    "blue",
+    "red",
+    "green4",
+    "darkorange",
+    "purple",
+    "brown",
+    "cyan4",
+    "magenta",
+];
+
+// Verified paths are green, failing ones red, and those Z3 couldn't decide orange
+fn outcome_color(outcome: &VerifyOutcome) -> &'static str {
+    match outcome {
+        VerifyOutcome::Valid => "green",
+        VerifyOutcome::Invalid(_) => "red",
+        VerifyOutcome::Unknown | VerifyOutcome::Timeout | VerifyOutcome::Error(_) => "orange",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    #[test]
+    fn paths_are_numbered_from_one_in_the_dot_graph() {
+        let ast = syn::parse_file(
+            "fn f(x: i32) -> i32 { pre!(x > 0); let mut y = x; if y > 5 { y = 5; } \
+             post!(y > 0); y }",
+        )
+        .unwrap();
+        let mut builder = CfgBuilder::with_default_config().unwrap();
+        builder.build_cfg(&ast);
+        let paths = builder.generate_basic_paths();
+        let dot = builder.to_dot_with_paths(&paths);
+        assert!(dot.contains("path 1") && dot.contains("path 2"));
+        assert!(!dot.contains("path 0") && !dot.contains("paths 0"));
+    }
+
+    #[test]
+    fn loop_exit_paths_assume_the_negated_guard() {
+        let source = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut i = 0; let mut s = 0; \
+            while i < n { invariant!(0 <= i && i <= n && 2 * s == i * (i - 1)); \
+            s = s + i; i = i + 1; } post!(2 * s == n * (n - 1)); s }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap()[1],
+            "invariant ! (0 <= i && i <= n && 2 * s == i * (i - 1)) >> ! (i < n) >> \
+             post ! (2 * s == n * (n - 1))"
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+        // Without 'i <= n' the negated guard alone doesn't give 'i == n' at the exit
+        let weaker = source.replace("i <= n && ", "");
+        assert!(path_outcomes(&weaker)[1].is_invalid());
     }
 }
@@ -1,6 +1,7 @@
 use crate::cfg_builder::builder::CfgBuilder;
 use crate::cfg_builder::node::CfgNode;
-use quote::quote;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
 use syn::{visit::Visit, Expr, ExprCall, ExprMethodCall, Stmt};
 
 impl CfgBuilder {
@@ -17,6 +18,7 @@ impl CfgBuilder {
         for arg in &expr_call.args {
             self.visit_expr(arg);
         }
+        self.add_call_contract(expr_call);
     }
 
     pub fn handle_method_call(&mut self, expr_method_call: &ExprMethodCall) {
@@ -29,11 +31,19 @@ impl CfgBuilder {
             .cloned();
 
         if let Some(external_method) = maybe_external_method {
-            // Add preconditions before the method call
-            for pre in external_method.preconditions {
-                self.add_node(CfgNode::new_precondition(
-                    pre,
-                    Expr::MethodCall(expr_method_call.clone()),
+            // Like the contract of a called function, the preconditions have to hold before
+            // the method call and the postconditions hold after it
+            let span = expr_method_call.span();
+            let call_str = Self::clean_up_formatting(&quote!(#expr_method_call).to_string());
+            for pre in &external_method.preconditions {
+                let Some(cond) = Self::external_condition(&external_method.name, pre) else {
+                    continue;
+                };
+                let check = syn::parse2(quote_spanned!(span=> assert!(#cond)))
+                    .expect("Failed to build external precondition");
+                self.add_node(CfgNode::new_call_precondition(
+                    format!("{}: {}", call_str, pre),
+                    check,
                 ));
             }
 
@@ -43,11 +53,15 @@ impl CfgBuilder {
             let call_statement = Stmt::Expr(Expr::MethodCall(expr_method_call.clone()));
             self.add_node(CfgNode::new_statement(call_description, call_statement));
 
-            // Add postconditions after the method call
-            for post in external_method.postconditions {
-                self.add_node(CfgNode::new_postcondition(
-                    post,
-                    Expr::MethodCall(expr_method_call.clone()),
+            for post in &external_method.postconditions {
+                let Some(cond) = Self::external_condition(&external_method.name, post) else {
+                    continue;
+                };
+                let assumption = syn::parse2(quote_spanned!(span=> assume!(#cond)))
+                    .expect("Failed to build external postcondition");
+                self.add_node(CfgNode::new_assumption(
+                    format!("{}: {}", call_str, post),
+                    assumption,
                 ));
             }
         } else {
@@ -58,4 +72,19 @@ impl CfgBuilder {
             self.add_node(CfgNode::new_statement(call_description, call_statement));
         }
     }
+
+    // Condition of an external method's contract, which may come from code rather than a
+    // validated conditions file
+    fn external_condition(method: &str, condition: &str) -> Option<Expr> {
+        syn::parse_str(condition)
+            .map_err(|e| {
+                log::warn!(
+                    "Ignoring condition '{}' of external method {}: {}",
+                    condition,
+                    method,
+                    e
+                )
+            })
+            .ok()
+    }
 }
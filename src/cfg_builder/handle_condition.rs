@@ -2,39 +2,42 @@ use syn::ExprIf;
 
 use crate::cfg_builder::builder::CfgBuilder;
 use crate::cfg_builder::node::{CfgNode, ConditionalExpr};
+use petgraph::graph::NodeIndex;
 use proc_macro2::Span;
 use quote::quote;
-use syn::{token, visit::Visit, Expr, ExprParen, ExprUnary, Pat, UnOp};
+use syn::{token, visit::Visit, BinOp, Expr, ExprBinary, ExprParen, ExprUnary, Pat, UnOp};
+
+// Condition nodes a branch is entered from, with the label of the edge to take
+pub type ConditionExits = Vec<(NodeIndex, String)>;
 
 impl CfgBuilder {
     pub fn handle_if_statement(&mut self, expr_if: &ExprIf) {
-        let cond_str = self.format_condition(&expr_if.cond);
-        let cond_label = if self.next_edge_label == Some("false".to_string()) {
-            format!("else if: {}", cond_str)
+        let label_prefix = if self.next_edge_label == Some("false".to_string()) {
+            "else if: "
         } else {
-            format!("if: {}", cond_str)
+            "if: "
         };
-        let cond_expr = ConditionalExpr::If(expr_if.cond.clone());
-        let cond_node = self.add_node(CfgNode::new_condition(cond_label, cond_expr));
+        let (true_exits, false_exits) =
+            self.add_condition_nodes(&expr_if.cond, label_prefix, ConditionalExpr::If);
 
         // Processing the true branch
-        self.next_edge_label = Some("true".to_string());
-        self.current_node = Some(cond_node.clone());
+        self.enter_branch(true_exits);
         self.visit_block(&expr_if.then_branch);
         let true_branch_end = self.current_node;
+        // An empty branch leaves the label of its edge pending
+        let true_branch_label = self.next_edge_label.take().unwrap_or_default();
 
         // Create a merge point node
         let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
 
         // Connect the true branch end to the merge point
         if let Some(true_end) = true_branch_end {
-            self.add_edge_with_label(true_end, merge_node, "".to_string());
+            self.add_edge_with_label(true_end, merge_node, true_branch_label);
         }
 
         // Handling the else branch if present
         if let Some((_, else_branch)) = &expr_if.else_branch {
-            self.current_node = Some(cond_node.clone());
-            self.next_edge_label = Some("false".to_string());
+            self.enter_branch(false_exits);
             match &**else_branch {
                 Expr::If(elseif) => {
                     // Handle else if with recursion
@@ -50,16 +53,94 @@ impl CfgBuilder {
 
             // Connect the end of the else branch to the merge point
             if let Some(false_end) = self.current_node {
-                self.add_edge_with_label(false_end, merge_node, "".to_string());
+                let label = self.next_edge_label.take().unwrap_or_default();
+                self.add_edge_with_label(false_end, merge_node, label);
             }
         } else {
             // If there is no else branch, connect the condition node to the merge point with a 'false' label
-            self.add_edge_with_label(cond_node, merge_node, "false".to_string());
+            for (cond_node, label) in false_exits {
+                self.add_edge_with_label(cond_node, merge_node, label);
+            }
         }
 
         // Continue from the merge point after if-else
         self.current_node = Some(merge_node);
     }
+
+    // Adds the condition nodes of 'cond' from the current node. 'a && b' and 'a || b' are split
+    // the way Rust evaluates them, 'b' is only reached when 'a' doesn't decide the branch.
+    // So paths through 'i < n && a[i] > 0' only read 'a[i]' once 'i < n' holds.
+    // Returns the exits to the branch taken when 'cond' holds and to the one taken otherwise.
+    pub fn add_condition_nodes(
+        &mut self,
+        cond: &Expr,
+        label_prefix: &str,
+        conditional_expr: fn(Box<Expr>) -> ConditionalExpr,
+    ) -> (ConditionExits, ConditionExits) {
+        let mut inner = cond;
+        while let Expr::Paren(expr_paren) = inner {
+            inner = &expr_paren.expr;
+        }
+        match inner {
+            Expr::Binary(ExprBinary {
+                left,
+                op: BinOp::And(_),
+                right,
+                ..
+            }) => {
+                let (left_true, mut false_exits) =
+                    self.add_condition_nodes(left, label_prefix, conditional_expr);
+                self.enter_branch(left_true);
+                let (true_exits, right_false) =
+                    self.add_condition_nodes(right, "&& ", conditional_expr);
+                false_exits.extend(right_false);
+                (true_exits, false_exits)
+            }
+            Expr::Binary(ExprBinary {
+                left,
+                op: BinOp::Or(_),
+                right,
+                ..
+            }) => {
+                let (mut true_exits, left_false) =
+                    self.add_condition_nodes(left, label_prefix, conditional_expr);
+                self.enter_branch(left_false);
+                let (right_true, false_exits) =
+                    self.add_condition_nodes(right, "|| ", conditional_expr);
+                true_exits.extend(right_true);
+                (true_exits, false_exits)
+            }
+            Expr::Let(expr_let) => {
+                self.add_let_condition_node(expr_let, label_prefix, conditional_expr)
+            }
+            _ => {
+                let cond_str = self.format_condition(&Box::new(cond.clone()));
+                let cond_node = self.add_node(CfgNode::new_condition(
+                    format!("{}{}", label_prefix, cond_str),
+                    conditional_expr(Box::new(cond.clone())),
+                ));
+                (
+                    vec![(cond_node, "true".to_string())],
+                    vec![(cond_node, "false".to_string())],
+                )
+            }
+        }
+    }
+
+    // Continues from the exits of a condition, joined by a merge point if there are several
+    pub fn enter_branch(&mut self, exits: ConditionExits) {
+        if let [(cond_node, label)] = exits.as_slice() {
+            self.current_node = Some(*cond_node);
+            self.next_edge_label = Some(label.clone());
+            return;
+        }
+        let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
+        for (cond_node, label) in exits {
+            self.add_edge_with_label(cond_node, merge_node, label);
+        }
+        self.next_edge_label = None;
+    }
+
     pub fn format_pattern_condition(&self, pat: &Pat) -> String {
         let raw_string = quote!(#pat).to_string();
         Self::clean_up_formatting(&raw_string)
@@ -83,4 +164,127 @@ impl CfgBuilder {
 
         Expr::Unary(not_expr)
     }
+
+    // Negation of the condition of a false branch, pushed inwards if 'de_morgan' is set
+    pub fn negate_branch_condition(&self, expr: Expr) -> Expr {
+        if self.de_morgan {
+            Self::push_negation(&expr)
+        } else {
+            Self::negate_condition(expr)
+        }
+    }
+
+    // Negation with '!' pushed down to the comparisons by De Morgan's laws, e.g. 'i >= n || sum < 0'
+    // for 'i < n && sum >= 0'. Anything else, like a call or a chained comparison, gets '!(...)'.
+    pub fn push_negation(expr: &Expr) -> Expr {
+        match expr {
+            Expr::Paren(expr_paren) => Self::push_negation(&expr_paren.expr),
+            Expr::Unary(ExprUnary {
+                op: UnOp::Not(_),
+                expr,
+                ..
+            }) => Self::parenthesize(*expr.clone()),
+            Expr::Binary(expr_binary) => {
+                let op = match expr_binary.op {
+                    BinOp::And(_) => BinOp::Or(Default::default()),
+                    BinOp::Or(_) => BinOp::And(Default::default()),
+                    _ => match Self::negated_comparison(expr_binary) {
+                        Some(op) => op,
+                        None => return Self::negate_condition(expr.clone()),
+                    },
+                };
+                let (left, right) = match expr_binary.op {
+                    BinOp::And(_) | BinOp::Or(_) => (
+                        Self::push_negation(&expr_binary.left),
+                        Self::push_negation(&expr_binary.right),
+                    ),
+                    _ => (*expr_binary.left.clone(), *expr_binary.right.clone()),
+                };
+                // Parenthesized so the result reads the same wherever it is printed
+                Self::parenthesize(Expr::Binary(ExprBinary {
+                    attrs: Vec::new(),
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(right),
+                }))
+            }
+            _ => Self::negate_condition(expr.clone()),
+        }
+    }
+
+    // Opposite comparison operator, unless an operand is a comparison itself: in annotations
+    // 'a <= b < c' is a chain, which negating the last operator would change
+    fn negated_comparison(expr_binary: &ExprBinary) -> Option<BinOp> {
+        let is_comparison = |expr: &Expr| match expr {
+            Expr::Binary(operand) => matches!(
+                operand.op,
+                BinOp::Eq(_)
+                    | BinOp::Ne(_)
+                    | BinOp::Lt(_)
+                    | BinOp::Le(_)
+                    | BinOp::Gt(_)
+                    | BinOp::Ge(_)
+            ),
+            _ => false,
+        };
+        if is_comparison(&expr_binary.left) || is_comparison(&expr_binary.right) {
+            return None;
+        }
+        match expr_binary.op {
+            BinOp::Lt(_) => Some(BinOp::Ge(Default::default())),
+            BinOp::Le(_) => Some(BinOp::Gt(Default::default())),
+            BinOp::Gt(_) => Some(BinOp::Le(Default::default())),
+            BinOp::Ge(_) => Some(BinOp::Lt(Default::default())),
+            BinOp::Eq(_) => Some(BinOp::Ne(Default::default())),
+            BinOp::Ne(_) => Some(BinOp::Eq(Default::default())),
+            _ => None,
+        }
+    }
+
+    fn parenthesize(expr: Expr) -> Expr {
+        Expr::Paren(ExprParen {
+            attrs: Vec::new(),
+            paren_token: token::Paren(Span::call_site()),
+            expr: Box::new(expr),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, verify_str, VerifyOutcome};
+    use quote::quote;
+
+    #[test]
+    fn negations_pushed_inwards_are_equivalent() {
+        for (condition, pushed) in [
+            ("i < n && sum >= 0", "((i >= n) || (sum < 0))"),
+            ("!(x == 1) || y != 2", "(((x == 1)) && (y == 2))"),
+            ("a <= b < c", "! (a <= b < c)"),
+        ] {
+            let expr: syn::Expr = syn::parse_str(condition).unwrap();
+            let negation = CfgBuilder::push_negation(&expr);
+            assert_eq!(quote!(#negation).to_string(), pushed);
+            let equivalence = format!("pre!(true) >> post!((!({})) == ({}))", condition, pushed);
+            assert!(matches!(verify_str(&equivalence), VerifyOutcome::Valid));
+        }
+    }
+
+    #[test]
+    fn right_operands_of_conjunctions_are_guarded_by_the_left_ones() {
+        let source = "fn f(a: [i32; 4], i: usize, n: usize) -> i32 { pre!(n == 4); let mut r = 0; \
+                      if i < n && a[i] > 0 { r = a[i]; } post!(r >= 0); r }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (n == 4) >> ! (i < n) >> post ! (0 >= 0)",
+                "pre ! (n == 4) >> (i < n) >> ! (a [i] > 0) >> post ! (0 >= 0)",
+                "pre ! (n == 4) >> (i < n) >> (a [i] > 0) >> post ! ((a [i]) >= 0)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
 }
@@ -1,88 +1,418 @@
-use syn::{visit::Visit, ExprWhile};
-
-use crate::cfg_builder::builder::CfgBuilder;
-use crate::cfg_builder::node::{CfgNode, ConditionalExpr};
-
-impl CfgBuilder {
-    pub fn handle_for_loop(&mut self, expr_for: &syn::ExprForLoop) {
-        // Check if the last node was an invariant
-        let invariant_node = self
-            .current_node
-            .filter(|&current| matches!(self.graph[current], CfgNode::Invariant(_, _)));
-
-        let loop_back_node;
-
-        if invariant_node.is_none() {
-            // Add the "@Cutoff" node if no invariant is present
-            let cutoff_node = self.add_node(CfgNode::new_cutoff("".to_string()));
-            loop_back_node = cutoff_node;
-        } else {
-            loop_back_node = invariant_node.unwrap();
-        }
-
-        let loop_var = self.format_pattern_condition(&expr_for.pat);
-        let iterator = self.format_condition(&expr_for.expr);
-        let cond_label = format!("for {} in {}", loop_var, iterator);
-        let cond_expr = ConditionalExpr::ForLoop(expr_for.clone());
-        let cond_node = self.add_node(CfgNode::new_condition(cond_label, cond_expr));
-
-        // Process the loop body
-        self.current_node = Some(cond_node);
-        self.next_edge_label = Some("true".to_string());
-        self.visit_block(&expr_for.body);
-
-        // Link back to the loop_back_node after the loop body
-        if let Some(end_node) = self.current_node {
-            self.add_edge_with_label(end_node, loop_back_node, "back to loop".to_string());
-        }
-
-        // Create a merge node for the exit of the loop
-        let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
-        self.add_edge_with_label(cond_node, merge_node, "false".to_string());
-
-        // Continue from the merge point after the loop
-        self.current_node = Some(merge_node);
-    }
-
-    pub fn handle_while_loop(&mut self, expr_while: &ExprWhile) {
-        // Check if the last node was an invariant
-        let invariant_node = self
-            .current_node
-            .filter(|&current| matches!(self.graph[current], CfgNode::Invariant(_, _)));
-
-        let loop_back_node;
-
-        if invariant_node.is_none() {
-            // Add the "@Cutoff" node if no invariant is present
-            let cutoff_node = self.add_node(CfgNode::new_cutoff("".to_string()));
-            loop_back_node = cutoff_node;
-        } else {
-            loop_back_node = invariant_node.unwrap();
-        }
-
-        // Add the "while" condition node
-        let cond_str = self.format_condition(&expr_while.cond);
-        let cond_expr = ConditionalExpr::While(expr_while.cond.clone());
-        let cond_node = self.add_node(CfgNode::new_condition(
-            format!("while: {}", cond_str),
-            cond_expr,
-        ));
-
-        // Process the loop body
-        self.current_node = Some(cond_node);
-        self.next_edge_label = Some("true".to_string());
-        self.visit_block(&expr_while.body);
-
-        // Link back to the loop_back_node after the loop body
-        if let Some(end_node) = self.current_node {
-            self.add_edge_with_label(end_node, loop_back_node, "back to loop".to_string());
-        }
-
-        // Create a merge node for the false branch of the condition
-        let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
-        self.add_edge_with_label(cond_node, merge_node, "false".to_string());
-
-        // Continue from the merge point after the loop
-        self.current_node = Some(merge_node);
-    }
-}
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+use quote::quote;
+use syn::{
+    visit::Visit, Block, Expr, ExprBreak, ExprContinue, ExprForLoop, ExprLoop, ExprWhile, Ident,
+    Label, Lifetime, Pat, RangeLimits, Stmt,
+};
+
+use crate::cfg_builder::builder::{CfgBuilder, LoopContext};
+use crate::cfg_builder::node::{CfgNode, ConditionalExpr};
+
+// Loop variable and bounds of a 'for' loop over a range 'start..end' or 'start..=end'
+pub struct ForRange {
+    pub var: Ident,
+    pub start: Expr,
+    pub end: Expr,
+    pub inclusive: bool,
+}
+
+impl ForRange {
+    pub fn of(expr_for: &ExprForLoop) -> Option<Self> {
+        let var = match &expr_for.pat {
+            Pat::Ident(pat_ident) if pat_ident.subpat.is_none() => pat_ident.ident.clone(),
+            _ => return None,
+        };
+        let mut iterator = &*expr_for.expr;
+        while let Expr::Paren(expr_paren) = iterator {
+            iterator = &expr_paren.expr;
+        }
+        match iterator {
+            Expr::Range(range) => Some(ForRange {
+                var,
+                start: *range.from.clone()?,
+                end: *range.to.clone()?,
+                inclusive: matches!(range.limits, RangeLimits::Closed(_)),
+            }),
+            _ => None,
+        }
+    }
+
+    // Condition of the iterations, 'start <= i && i < end'
+    pub fn guard(&self) -> Expr {
+        let (var, start, in_range) = (&self.var, &self.start, self.in_range());
+        syn::parse2(quote!(#start <= #var && #in_range)).expect("Failed to build loop guard")
+    }
+
+    // 'i < end', the loop is left once it fails
+    pub fn in_range(&self) -> Expr {
+        let (var, end) = (&self.var, &self.end);
+        let in_range = if self.inclusive {
+            quote!(#var <= #end)
+        } else {
+            quote!(#var < #end)
+        };
+        syn::parse2(in_range).expect("Failed to build loop bound")
+    }
+
+    fn assignment(&self, value: Expr) -> CfgNode {
+        let var = &self.var;
+        let assign: Expr =
+            syn::parse2(quote!(#var = #value)).expect("Failed to build loop assignment");
+        CfgNode::new_statement(
+            quote!(#assign).to_string(),
+            Stmt::Semi(assign, Default::default()),
+        )
+    }
+}
+
+impl CfgBuilder {
+    // A loop over a range 'start..end' is modeled as a counter: 'i = start' before the loop,
+    // 'i = i + 1' after each iteration, and it goes on while 'i < end'. The bounds are read
+    // where they are used, so the loop shouldn't change them.
+    // Loops over other iterators branch without knowing anything about their variable.
+    pub fn handle_for_loop(&mut self, expr_for: &syn::ExprForLoop) {
+        let body = self.hoist_body_invariant(&expr_for.body);
+        let range = ForRange::of(expr_for);
+        if let Some(range) = &range {
+            self.add_loop_entry(range.assignment(range.start.clone()));
+        }
+        let loop_back_node = self.add_loop_back_node();
+
+        let loop_var = self.format_pattern_condition(&expr_for.pat);
+        let iterator = self.format_condition(&expr_for.expr);
+        let cond_label = format!("for {} in {}", loop_var, iterator);
+        let cond_expr = ConditionalExpr::ForLoop(expr_for.clone());
+        let cond_node = self.add_node(CfgNode::new_condition(cond_label, cond_expr));
+
+        // Process the loop body
+        self.current_node = Some(cond_node);
+        self.next_edge_label = Some("true".to_string());
+        let var = range.as_ref().map(|range| range.var.clone());
+        let increment = range.map(|range| range.assignment(syn::parse_quote!(#var + 1)));
+        self.enter_loop(&expr_for.label, loop_back_node);
+        self.loop_stack.last_mut().unwrap().increment = increment.clone();
+        self.visit_stmts(body);
+        let loop_context = self.exit_loop();
+
+        // Link back to the loop_back_node after the loop body
+        if self.current_node.is_some() {
+            if let Some(increment) = increment {
+                self.add_node(increment);
+            }
+        }
+        if let Some(end_node) = self.current_node {
+            self.add_edge_with_label(end_node, loop_back_node, "back to loop".to_string());
+        }
+
+        // Create a merge node for the exit of the loop
+        let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
+        self.add_edge_with_label(cond_node, merge_node, "false".to_string());
+        self.connect_breaks(loop_context, merge_node);
+
+        // Continue from the merge point after the loop
+        self.current_node = Some(merge_node);
+    }
+
+    pub fn handle_while_loop(&mut self, expr_while: &ExprWhile) {
+        let body = self.hoist_body_invariant(&expr_while.body);
+        let loop_back_node = self.add_loop_back_node();
+
+        // Add the "while" condition nodes
+        let (true_exits, false_exits) =
+            self.add_condition_nodes(&expr_while.cond, "while: ", ConditionalExpr::While);
+
+        // Process the loop body
+        self.enter_branch(true_exits);
+        self.enter_loop(&expr_while.label, loop_back_node);
+        self.visit_stmts(body);
+        let loop_context = self.exit_loop();
+
+        // Link back to the loop_back_node after the loop body
+        if let Some(end_node) = self.current_node {
+            self.add_edge_with_label(end_node, loop_back_node, "back to loop".to_string());
+        }
+
+        // Create a merge node for the false branch of the condition
+        let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
+        for (cond_node, label) in false_exits {
+            self.add_edge_with_label(cond_node, merge_node, label);
+        }
+        self.connect_breaks(loop_context, merge_node);
+
+        // Continue from the merge point after the loop
+        self.current_node = Some(merge_node);
+    }
+
+    pub fn handle_loop(&mut self, expr_loop: &ExprLoop) {
+        let body = self.hoist_body_invariant(&expr_loop.body);
+        let loop_back_node = self.add_loop_back_node();
+
+        // Process the loop body, it's entered unconditionally
+        self.current_node = Some(loop_back_node);
+        self.enter_loop(&expr_loop.label, loop_back_node);
+        self.visit_stmts(body);
+        let loop_context = self.exit_loop();
+
+        // Link back to the loop_back_node after the loop body
+        if let Some(end_node) = self.current_node {
+            self.add_edge_with_label(end_node, loop_back_node, "back to loop".to_string());
+        }
+
+        // The loop can only be left through 'break'
+        let merge_node = self.add_node_without_edge(CfgNode::MergePoint);
+        self.connect_breaks(loop_context, merge_node);
+
+        // Continue from the merge point after the loop
+        self.current_node = Some(merge_node);
+    }
+
+    // An 'invariant!' written as the first statement of the loop body is the loop's invariant,
+    // as if it came right before the loop, unless the loop already has one there.
+    // Returns the statements of the body left to visit.
+    fn hoist_body_invariant<'b>(&mut self, body: &'b Block) -> &'b [Stmt] {
+        if !self.at_invariant() {
+            if let Some(
+                Stmt::Semi(Expr::Macro(expr_macro), _) | Stmt::Expr(Expr::Macro(expr_macro)),
+            ) = body.stmts.first()
+            {
+                if expr_macro.mac.path.is_ident("invariant") {
+                    self.process_macro(expr_macro);
+                    return &body.stmts[1..];
+                }
+            }
+        }
+        &body.stmts
+    }
+
+    fn at_invariant(&self) -> bool {
+        self.current_node
+            .is_some_and(|current| matches!(self.graph[current], CfgNode::Invariant(_, _)))
+    }
+
+    fn visit_stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.visit_stmt(stmt);
+        }
+    }
+
+    // Adds a node run once before a loop, before its invariant if it has one
+    fn add_loop_entry(&mut self, node: CfgNode) {
+        let invariant_node = self
+            .current_node
+            .filter(|&current| matches!(self.graph[current], CfgNode::Invariant(_, _)));
+        let Some(invariant_node) = invariant_node else {
+            self.add_node(node);
+            return;
+        };
+
+        // The body isn't built yet, so the edges into the invariant all come from before the loop
+        let entry_node = self.graph.add_node(node);
+        let incoming: Vec<_> = self
+            .graph
+            .edges_directed(invariant_node, petgraph::Direction::Incoming)
+            .map(|edge| (edge.id(), edge.source(), edge.weight().clone()))
+            .collect();
+        for (edge, source, label) in incoming {
+            self.graph.remove_edge(edge);
+            self.graph.add_edge(source, entry_node, label);
+        }
+        self.graph
+            .add_edge(entry_node, invariant_node, "".to_string());
+    }
+
+    // Node the end of the loop body links back to: the invariant right before
+    // the loop if there is one, a "@Cutoff" node otherwise
+    fn add_loop_back_node(&mut self) -> NodeIndex {
+        let invariant_node = self
+            .current_node
+            .filter(|&current| matches!(self.graph[current], CfgNode::Invariant(_, _)));
+
+        match invariant_node {
+            Some(invariant_node) => invariant_node,
+            None => self.add_node(CfgNode::new_cutoff("".to_string())),
+        }
+    }
+
+    pub fn handle_break(&mut self, expr_break: &ExprBreak) {
+        let break_node = self.add_jump_node(Expr::Break(expr_break.clone()));
+        match self.find_loop(&expr_break.label) {
+            Some(loop_context) => loop_context.break_nodes.push(break_node),
+            None => log::warn!("'break' outside of a loop is ignored"),
+        }
+        // Nothing after the break is reachable
+        self.current_node = None;
+    }
+
+    pub fn handle_continue(&mut self, expr_continue: &ExprContinue) {
+        // 'continue' in a loop over a range moves on to the next value
+        let increment = self
+            .find_loop(&expr_continue.label)
+            .and_then(|loop_context| loop_context.increment.clone());
+        if let Some(increment) = increment {
+            self.add_node(increment);
+        }
+        let continue_node = self.add_jump_node(Expr::Continue(expr_continue.clone()));
+        match self.find_loop(&expr_continue.label) {
+            // Same label as the end of the body so the path is treated as a loop path
+            Some(loop_context) => {
+                let loop_back_node = loop_context.loop_back_node;
+                self.add_edge_with_label(continue_node, loop_back_node, "back to loop".to_string());
+            }
+            None => log::warn!("'continue' outside of a loop is ignored"),
+        }
+        // Nothing after the continue is reachable
+        self.current_node = None;
+    }
+
+    fn add_jump_node(&mut self, expr: Expr) -> NodeIndex {
+        let expr_str = quote!(#expr).to_string();
+        self.add_node(CfgNode::new_statement(expr_str, Stmt::Expr(expr)))
+    }
+
+    fn enter_loop(&mut self, label: &Option<Label>, loop_back_node: NodeIndex) {
+        self.loop_stack.push(LoopContext {
+            label: label.as_ref().map(|label| label.name.ident.to_string()),
+            loop_back_node,
+            break_nodes: Vec::new(),
+            increment: None,
+        });
+    }
+
+    fn exit_loop(&mut self) -> LoopContext {
+        self.loop_stack
+            .pop()
+            .expect("Loop context missing after processing loop body")
+    }
+
+    // Innermost loop, or the loop with the given label
+    fn find_loop(&mut self, label: &Option<Lifetime>) -> Option<&mut LoopContext> {
+        match label {
+            Some(lifetime) => {
+                let name = lifetime.ident.to_string();
+                self.loop_stack
+                    .iter_mut()
+                    .rev()
+                    .find(|loop_context| loop_context.label.as_deref() == Some(name.as_str()))
+            }
+            None => self.loop_stack.last_mut(),
+        }
+    }
+
+    fn connect_breaks(&mut self, loop_context: LoopContext, exit_node: NodeIndex) {
+        for break_node in loop_context.break_nodes {
+            self.add_edge_with_label(break_node, exit_node, "break".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    const LOOP_SOURCE: &str = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut i = 0; \
+        loop { invariant!(i <= n); if i >= n { break; } i = i + 1; } post!(i == n); i }";
+
+    #[test]
+    fn loops_are_left_only_by_break() {
+        let mut builder = CfgBuilder::with_default_config().unwrap();
+        builder.build_cfg(&syn::parse_file(LOOP_SOURCE).unwrap());
+        let dot = builder.to_dot();
+        // The last statement of the body loops back to the invariant, and the 'break' leaves
+        // for the tail expression before the postcondition
+        assert!(dot.contains("7 -> 3 [label=\"back to loop\"];"));
+        assert!(dot.contains("5 -> 8 [label=\"break\"];"));
+        assert!(!dot.contains("3 -> 8"));
+        assert!(path_outcomes(LOOP_SOURCE)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn range_loops_bound_their_variable() {
+        let source = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut s = 0; \
+            for i in 0..n { invariant!(2 * s == i * (i - 1) && i <= n); s = s + i; } \
+            post!(2 * s == n * (n - 1)); s }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (n >= 0) >> invariant ! (2 * 0 == 0 * (0 - 1) && 0 <= n)",
+                "invariant ! (2 * s == i * (i - 1) && i <= n) >> ! (i < n) >> \
+                 post ! (2 * s == n * (n - 1))",
+                "invariant ! (2 * s == i * (i - 1) && i <= n) >> (0 <= i && i < n) >> \
+                 invariant ! (2 * (s + i) == (i + 1) * ((i + 1) - 1) && (i + 1) <= n)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn for_loops_exit_when_their_guard_fails() {
+        let source = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut s = 0; \
+            for i in 0..=n { invariant!(s == i && i <= n + 1); s = s + 1; } \
+            post!(s == n + 1); s }";
+        // The exit negates the bound of the range, not the iterator expression
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap()[1],
+            "invariant ! (s == i && i <= n + 1) >> ! (i <= n) >> post ! (s == n + 1)"
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+        // Without an upper bound on 'i' the exit can't conclude 'i == n + 1'
+        let weaker = source.replace(" && i <= n + 1", "");
+        assert!(path_outcomes(&weaker)[1].is_invalid());
+    }
+
+    #[test]
+    fn for_loop_invariants_are_found_before_and_inside_the_loop() {
+        let inside = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut s = 0; \
+            for i in 0..n { invariant!(s >= 0); s = s + 1; } post!(s >= 0); s }";
+        let before = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut s = 0; invariant!(s >= 0); \
+            for i in 0..n { s = s + 1; } post!(s >= 0); s }";
+        for source in [inside, before] {
+            assert_eq!(
+                CfgBuilder::verification_conditions(source).unwrap(),
+                [
+                    "pre ! (n >= 0) >> invariant ! (0 >= 0)",
+                    "invariant ! (s >= 0) >> ! (i < n) >> post ! (s >= 0)",
+                    "invariant ! (s >= 0) >> (0 <= i && i < n) >> invariant ! ((s + 1) >= 0)",
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn length_guarded_loops_verify() {
+        let source = "fn f(v: Vec<i32>) -> usize { pre!(true); let mut c = 0; \
+            while c < v.len() { invariant!(c <= v.len()); c = c + 1; } post!(c == v.len()); c }";
+        let outcomes = path_outcomes(source);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn labeled_breaks_leave_the_outer_loop() {
+        let source = "fn f(n: i32) -> i32 { pre!(n >= 0); let mut i = 0; let mut found = 0; \
+            'outer: while i < n { invariant!(0 <= i && i <= n && found == 0); let mut j = 0; \
+            while j < n { invariant!(0 <= i && i < n && j <= n && found == 0); \
+            if i + j == 7 { found = 1; break 'outer; } j = j + 1; } i = i + 1; } \
+            post!(found == 1 || i == n); i }";
+        let conditions = CfgBuilder::verification_conditions(source).unwrap();
+        assert_eq!(
+            conditions.last().unwrap(),
+            "invariant ! (0 <= i && i < n && j <= n && found == 0) >> (j < n) >> \
+             (i + j == 7) >> post ! (1 == 1 || i == n)"
+        );
+        let outcomes = path_outcomes(source);
+        assert_eq!(outcomes.len(), 6);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+}
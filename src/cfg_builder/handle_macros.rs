@@ -1,31 +1,113 @@
-use syn::{ExprMacro, punctuated::Punctuated, Expr, token::Comma};
-use quote::quote;
-use crate::cfg_builder::builder::CfgBuilder;
-use crate::cfg_builder::node::CfgNode;
-
-impl CfgBuilder {
-    pub fn process_macro(&mut self, expr_macro: &ExprMacro) {
-        let macro_name = format!("{}!", expr_macro.mac.path.segments.last().unwrap().ident);
-        self.process_external_conditions(&macro_name, quote!(#expr_macro).to_string());
-    }
-
-    pub fn process_macro_call_as_function(&mut self, args: &Punctuated<Expr, Comma>, macro_name: &str) {
-        let call_expression = format!("{}[{}]", macro_name, quote!(#args));
-        self.process_external_conditions(macro_name, call_expression);
-    }
-
-    pub fn process_external_conditions(&mut self, name: &str, call_expression: String) {
-        let external_methods = self.external_conditions.external_methods.clone();
-        if let Some(external_method) = external_methods.iter().find(|m| m.name == name) {
-            for pre in &external_method.preconditions {
-                self.add_node(CfgNode::new_precondition(pre.clone(), Expr::Verbatim(quote!(#pre).into())));
-            }
-            self.add_node(CfgNode::Statement(format!("Call: {}", call_expression), None));
-            for post in &external_method.postconditions {
-                self.add_node(CfgNode::new_postcondition(post.clone(), Expr::Verbatim(quote!(#post).into())));
-            }
-        } else {
-            self.add_node(CfgNode::Statement(format!("Call: {}", call_expression), None));
-        }
-    }
-}
+use crate::cfg_builder::builder::CfgBuilder;
+use crate::cfg_builder::node::CfgNode;
+use quote::quote;
+use syn::{punctuated::Punctuated, token::Comma, Expr, ExprMacro};
+
+impl CfgBuilder {
+    pub fn process_macro(&mut self, expr_macro: &ExprMacro) {
+        // 'assert!' nested in branches and loops is an inline assertion too
+        if expr_macro.mac.path.is_ident("assert") {
+            let assertion = self.format_macro_args(&expr_macro.mac.tokens);
+            self.add_node(CfgNode::new_assertion(
+                assertion,
+                Expr::Macro(expr_macro.clone()),
+            ));
+            return;
+        }
+        // 'invariant!' before a nested loop
+        if expr_macro.mac.path.is_ident("invariant") {
+            let invariant = self.format_macro_args(&expr_macro.mac.tokens);
+            self.add_node(CfgNode::new_invariant(
+                invariant,
+                Expr::Macro(expr_macro.clone()),
+            ));
+            return;
+        }
+        if expr_macro.mac.path.is_ident("assume") {
+            let assumption = self.format_macro_args(&expr_macro.mac.tokens);
+            self.add_node(CfgNode::new_assumption(
+                assumption,
+                Expr::Macro(expr_macro.clone()),
+            ));
+            return;
+        }
+        let macro_name = format!("{}!", expr_macro.mac.path.segments.last().unwrap().ident);
+        self.process_external_conditions(&macro_name, quote!(#expr_macro).to_string());
+    }
+
+    pub fn process_macro_call_as_function(
+        &mut self,
+        args: &Punctuated<Expr, Comma>,
+        macro_name: &str,
+    ) {
+        let call_expression = format!("{}[{}]", macro_name, quote!(#args));
+        self.process_external_conditions(macro_name, call_expression);
+    }
+
+    pub fn process_external_conditions(&mut self, name: &str, call_expression: String) {
+        let external_methods = self.external_conditions.external_methods.clone();
+        if let Some(external_method) = external_methods.iter().find(|m| m.name == name) {
+            for pre in &external_method.preconditions {
+                self.add_node(CfgNode::new_precondition(
+                    pre.clone(),
+                    Expr::Verbatim(quote!(#pre)),
+                ));
+            }
+            self.add_node(CfgNode::Statement(
+                format!("Call: {}", call_expression),
+                None,
+            ));
+            for post in &external_method.postconditions {
+                self.add_node(CfgNode::new_postcondition(
+                    post.clone(),
+                    Expr::Verbatim(quote!(#post)),
+                ));
+            }
+        } else {
+            self.add_node(CfgNode::Statement(
+                format!("Call: {}", call_expression),
+                None,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    #[test]
+    fn assertions_split_the_paths() {
+        let source = "fn f(x: i32) -> i32 { pre!(x > 0); let y = x + 1; assert!(y > 1); \
+                      let z = y * 2; post!(z > 2); z }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (x > 0) >> assert ! ((x + 1) > 1)",
+                "assert ! (y > 1) >> post ! ((y * 2) > 2)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+        let outcomes = path_outcomes(
+            "fn f(x: i32) -> i32 { pre!(x > 0); let y = x + 1; assert!(y > 5); post!(y > 0); y }",
+        );
+        assert!(outcomes[0].is_invalid());
+    }
+
+    #[test]
+    fn assumptions_are_not_checked() {
+        let source = "fn f(x: i32) -> i32 { pre!(true); assume!(x > 0); let y = x - 1; \
+                      post!(y >= 0); y }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            ["pre ! (true) >> assume ! (x > 0) >> post ! ((x - 1) >= 0)"]
+        );
+        assert!(matches!(path_outcomes(source)[..], [VerifyOutcome::Valid]));
+        let outcomes =
+            path_outcomes("fn f(x: i32) -> i32 { pre!(true); let y = x - 1; post!(y >= 0); y }");
+        assert!(outcomes[0].is_invalid());
+    }
+}
@@ -0,0 +1,257 @@
+use crate::cfg_builder::builder::CfgBuilder;
+use crate::cfg_builder::handle_condition::ConditionExits;
+use crate::cfg_builder::node::{CfgNode, ConditionalExpr};
+use petgraph::graph::NodeIndex;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    parse_quote_spanned, visit::Visit, Expr, ExprLet, ExprMatch, Ident, Pat, RangeLimits, Stmt,
+};
+
+// What checking a match arm pattern against the scrutinee amounts to
+enum PatternTest {
+    Irrefutable,     // always matches ('_' or a plain binding)
+    Test(Box<Expr>), // matches when the boolean expression holds
+    Opaque,          // refutable but can't be expressed as a condition (enum variants, structs...)
+}
+
+impl CfgBuilder {
+    pub fn handle_match(&mut self, expr_match: &ExprMatch) {
+        let scrutinee = &expr_match.expr;
+        let scrutinee_str = self.format_condition(scrutinee);
+
+        // Create the merge point all arms converge to
+        let merge_node = self.graph.add_node(CfgNode::MergePoint);
+
+        // Nodes whose 'false' edge leads to the next arm (pattern or guard didn't match)
+        let mut fallthrough: Vec<NodeIndex> = Vec::new();
+        let mut exhaustive = false;
+
+        for arm in &expr_match.arms {
+            if !fallthrough.is_empty() {
+                // Join the 'false' edges into a single entry, removed again in post_process
+                let entry_node = self.add_node_without_edge(CfgNode::MergePoint);
+                for source in fallthrough.drain(..) {
+                    self.add_edge_with_label(source, entry_node, "false".to_string());
+                }
+            }
+
+            let pat_str = self.format_pattern_condition(&arm.pat);
+            let cond_label = format!("match {}: {}", scrutinee_str, pat_str);
+            let pattern_test = Self::pattern_test(scrutinee, &arm.pat);
+            let is_irrefutable = matches!(pattern_test, PatternTest::Irrefutable);
+            match pattern_test {
+                PatternTest::Irrefutable => {}
+                PatternTest::Test(cond) => {
+                    let cond_expr = ConditionalExpr::Match(cond);
+                    let cond_node = self.add_node(CfgNode::new_condition(cond_label, cond_expr));
+                    fallthrough.push(cond_node);
+                    self.next_edge_label = Some(format!("case {}", pat_str));
+                }
+                PatternTest::Opaque => {
+                    // Keep the branch in the CFG without assuming anything about it
+                    let cond_node = self.add_node(CfgNode::Condition(cond_label, None));
+                    fallthrough.push(cond_node);
+                    self.next_edge_label = Some(format!("case {}", pat_str));
+                }
+            }
+
+            // Bind pattern identifiers to the scrutinee so they can be substituted
+            for binding in Self::pattern_bindings(&arm.pat) {
+                let local: Stmt = parse_quote_spanned!(binding.span()=> let #binding = #scrutinee;);
+                let local_str = quote!(#local).to_string();
+                self.add_node(CfgNode::new_statement(local_str, local));
+            }
+
+            if let Some((_, guard)) = &arm.guard {
+                let guard_str = self.format_condition(guard);
+                let cond_expr = ConditionalExpr::If(guard.clone());
+                let guard_node = self.add_node(CfgNode::new_condition(
+                    format!("guard: {}", guard_str),
+                    cond_expr,
+                ));
+                fallthrough.push(guard_node);
+                self.next_edge_label = Some("true".to_string());
+            }
+
+            // Process the arm body
+            match &*arm.body {
+                Expr::Block(block) => self.visit_block(&block.block),
+                Expr::Tuple(tuple) if tuple.elems.is_empty() => {}
+                body => self.visit_expr(body),
+            }
+
+            // Connect the end of the arm to the merge point
+            if let Some(arm_end) = self.current_node {
+                let label = self.next_edge_label.take().unwrap_or_default();
+                self.add_edge_with_label(arm_end, merge_node, label);
+            }
+
+            // Arms after an unguarded irrefutable pattern are unreachable
+            if is_irrefutable && arm.guard.is_none() {
+                exhaustive = true;
+                break;
+            }
+        }
+
+        // No arm matched: only reachable for patterns we couldn't model exhaustively
+        if !exhaustive {
+            for source in fallthrough.drain(..) {
+                self.add_edge_with_label(source, merge_node, "false".to_string());
+            }
+        }
+
+        // Continue from the merge point after the match
+        self.current_node = Some(merge_node);
+    }
+
+    // Condition node of 'if let pat = expr' or 'while let pat = expr', tested like a match arm.
+    // Bindings of the whole value ('x' or 'x @ 1..=5') are assigned in the branch taken when
+    // the pattern matches, the others (like 'x' in 'Some(x)') are left unconstrained.
+    pub fn add_let_condition_node(
+        &mut self,
+        expr_let: &ExprLet,
+        label_prefix: &str,
+        conditional_expr: fn(Box<Expr>) -> ConditionalExpr,
+    ) -> (ConditionExits, ConditionExits) {
+        let scrutinee = &expr_let.expr;
+        let cond_label = format!(
+            "{}let {} = {}",
+            label_prefix,
+            self.format_pattern_condition(&expr_let.pat),
+            self.format_condition(scrutinee)
+        );
+        let cond_node = match Self::pattern_test(scrutinee, &expr_let.pat) {
+            PatternTest::Test(cond) => {
+                self.add_node(CfgNode::new_condition(cond_label, conditional_expr(cond)))
+            }
+            PatternTest::Irrefutable => self.add_node(CfgNode::new_condition(
+                cond_label,
+                conditional_expr(Box::new(parse_quote_spanned!(expr_let.pat.span()=> true))),
+            )),
+            PatternTest::Opaque => self.add_node(CfgNode::Condition(cond_label, None)),
+        };
+
+        let mut true_exit = (cond_node, "true".to_string());
+        let bindings = Self::pattern_bindings(&expr_let.pat);
+        if !bindings.is_empty() {
+            self.next_edge_label = Some("true".to_string());
+            for binding in bindings {
+                let local: Stmt = parse_quote_spanned!(binding.span()=> let #binding = #scrutinee;);
+                let local_str = quote!(#local).to_string();
+                true_exit = (
+                    self.add_node(CfgNode::new_statement(local_str, local)),
+                    String::new(),
+                );
+            }
+        }
+        (vec![true_exit], vec![(cond_node, "false".to_string())])
+    }
+
+    // Tests carry the span of their pattern so paths are reported at the right line
+    fn pattern_test(scrutinee: &Expr, pat: &Pat) -> PatternTest {
+        match pat {
+            Pat::Wild(_) => PatternTest::Irrefutable,
+            Pat::Ident(pat_ident) => match &pat_ident.subpat {
+                // 'x @ pattern' matches whenever the sub-pattern does
+                Some((_, subpat)) => Self::pattern_test(scrutinee, subpat),
+                None if is_binding(&pat_ident.ident) => PatternTest::Irrefutable,
+                // Capitalized identifiers are constants or unit variants like 'None'
+                None => PatternTest::Opaque,
+            },
+            Pat::Lit(pat_lit) => {
+                let lit = &pat_lit.expr;
+                PatternTest::Test(Box::new(
+                    parse_quote_spanned!(pat.span()=> (#scrutinee) == #lit),
+                ))
+            }
+            Pat::Range(pat_range) => {
+                let (lo, hi) = (&pat_range.lo, &pat_range.hi);
+                match pat_range.limits {
+                    RangeLimits::HalfOpen(_) => PatternTest::Test(Box::new(
+                        parse_quote_spanned!(pat.span()=> (#scrutinee) >= #lo && (#scrutinee) < #hi),
+                    )),
+                    RangeLimits::Closed(_) => PatternTest::Test(Box::new(
+                        parse_quote_spanned!(pat.span()=> (#scrutinee) >= #lo && (#scrutinee) <= #hi),
+                    )),
+                }
+            }
+            Pat::Or(pat_or) => {
+                let mut conds = Vec::new();
+                for case in &pat_or.cases {
+                    match Self::pattern_test(scrutinee, case) {
+                        PatternTest::Irrefutable => return PatternTest::Irrefutable,
+                        PatternTest::Opaque => return PatternTest::Opaque,
+                        PatternTest::Test(cond) => conds.push(*cond),
+                    }
+                }
+                PatternTest::Test(Box::new(parse_quote_spanned!(pat.span()=> #((#conds))||*)))
+            }
+            _ => PatternTest::Opaque,
+        }
+    }
+
+    // Identifiers bound by a top-level 'x' or 'x @ pattern'
+    fn pattern_bindings(pat: &Pat) -> Vec<Ident> {
+        match pat {
+            Pat::Ident(pat_ident) if is_binding(&pat_ident.ident) => {
+                vec![pat_ident.ident.clone()]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+// Whether a pattern identifier binds a variable: 'x' or '_x' do, capitalized identifiers like
+// 'None' or 'MAX' are unit variants or constants
+pub fn is_binding(ident: &Ident) -> bool {
+    ident
+        .to_string()
+        .starts_with(|c: char| c.is_lowercase() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, run_verification_str, VerificationOptions, VerifyOutcome};
+
+    const SOURCE: &str = "fn f(x: i32) -> i32 { pre!(x >= 0); let mut y = 0; \
+        match x { 0 => { y = 1; } n if n > 10 => { y = 2; } _ => { y = 3; } } \
+        post!(y > 0 && (y != 2 || x > 10)); y }";
+
+    #[test]
+    fn each_arm_and_guard_is_a_path() {
+        assert_eq!(
+            CfgBuilder::verification_conditions(SOURCE).unwrap(),
+            [
+                "pre ! (x >= 0) >> ! (x == 0) >> ! (x > 10) >> post ! (3 > 0 && (3 != 2 || x > 10))",
+                "pre ! (x >= 0) >> ! (x == 0) >> (x > 10) >> post ! (2 > 0 && (2 != 2 || x > 10))",
+                "pre ! (x >= 0) >> (x == 0) >> post ! (1 > 0 && (1 != 2 || x > 10))",
+            ]
+        );
+        assert!(path_outcomes(SOURCE)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn if_let_and_while_let_paths_belong_to_their_function() {
+        let source = "fn f(o: Option<i32>) -> i32 { pre!(true); let mut r = 0; \
+            if let Some(x) = o { r = 1; } post!(r >= 0); r }
+fn g(n: i32) -> i32 { pre!(true); let mut r = 0; \
+            if let m @ 1..=5 = n { r = m; } post!(r >= 0 && r <= 5); r }
+fn h(n: i32) -> i32 { pre!(n >= 0); let mut k = n; \
+            while let 1..=100 = k { invariant!(k >= 0); k = k - 1; } post!(k == 0 || k > 100); k }";
+        let report = run_verification_str(source, &VerificationOptions::default()).unwrap();
+        let functions: Vec<_> = report
+            .paths
+            .iter()
+            .map(|path| path.source.as_ref().unwrap().function.as_str())
+            .collect();
+        assert_eq!(functions, ["f", "f", "g", "g", "h", "h", "h"]);
+        assert!(report
+            .paths
+            .iter()
+            .all(|path| matches!(path.outcome, VerifyOutcome::Valid)));
+    }
+}
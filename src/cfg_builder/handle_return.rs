@@ -1,11 +1,320 @@
-use syn::ExprReturn;
-use quote::quote;
-use crate::cfg_builder::{CfgBuilder, CfgNode};
+use crate::cfg_builder::{CfgBuilder, CfgNode, ConditionalExpr};
+use petgraph::graph::NodeIndex;
+use proc_macro2::{TokenStream, TokenTree};
+use quote::{quote, quote_spanned};
+use syn::{
+    spanned::Spanned,
+    visit::Visit,
+    visit_mut::{self, VisitMut},
+    Block, Expr, ExprClosure, ExprReturn, Item, Stmt,
+};
+
+// Name postconditions can give the value a function returns, e.g. 'post!(result >= 0)'
+pub const RESULT_BINDING: &str = "result";
+
+// Variable a function returns at its end, as its tail expression or with a final 'return x;'.
+// Postconditions talk about it, so early returns assign their value to it. When they mention
+// 'result', that is the variable, and the tail expression is assigned to it too.
+pub fn returned_binding(block: &Block) -> Option<String> {
+    if block.stmts.iter().any(is_postcondition_on_result) {
+        return Some(RESULT_BINDING.to_string());
+    }
+    let returned = match block.stmts.last()? {
+        Stmt::Expr(Expr::Return(expr_return)) | Stmt::Semi(Expr::Return(expr_return), _) => {
+            expr_return.expr.as_deref()?
+        }
+        Stmt::Expr(expr) => expr,
+        _ => return None,
+    };
+    match returned {
+        Expr::Path(expr_path) => expr_path.path.get_ident().map(|ident| ident.to_string()),
+        _ => None,
+    }
+}
+
+fn is_postcondition_on_result(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Semi(Expr::Macro(expr_macro), _) | Stmt::Expr(Expr::Macro(expr_macro)) => {
+            expr_macro.mac.path.is_ident("post") && mentions_result(&expr_macro.mac.tokens)
+        }
+        _ => false,
+    }
+}
+
+fn mentions_result(tokens: &TokenStream) -> bool {
+    tokens.clone().into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => ident == RESULT_BINDING,
+        TokenTree::Group(group) => mentions_result(&group.stream()),
+        _ => false,
+    })
+}
+
+// Turns the value of each branch of an 'if', 'match' or block into an assignment to 'result',
+// e.g. 'if c { x } else { 0 }' into 'if c { result = x; } else { result = 0; }'. Branches that
+// return or diverge are left as they are. False if a branch value couldn't be assigned, like
+// the one of a loop.
+fn assign_branch_values(expr: &mut Expr) -> bool {
+    match expr {
+        Expr::If(expr_if) => {
+            let then_assigned = assign_block_value(&mut expr_if.then_branch);
+            match &mut expr_if.else_branch {
+                Some((_, else_branch)) => assign_branch_values(else_branch) && then_assigned,
+                // Without an 'else' the 'if' has no value
+                None => false,
+            }
+        }
+        Expr::Match(expr_match) => expr_match.arms.iter_mut().fold(true, |assigned, arm| {
+            assign_value(&mut arm.body) && assigned
+        }),
+        Expr::Block(expr_block) => assign_block_value(&mut expr_block.block),
+        _ => false,
+    }
+}
+
+fn assign_block_value(block: &mut Block) -> bool {
+    match block.stmts.last_mut() {
+        Some(Stmt::Expr(expr)) => {
+            if matches!(expr, Expr::If(_) | Expr::Match(_) | Expr::Block(_)) {
+                return assign_branch_values(expr);
+            }
+            if !assign_value(expr) {
+                return false;
+            }
+            let assign = expr.clone();
+            *block.stmts.last_mut().unwrap() = Stmt::Semi(assign, Default::default());
+            true
+        }
+        // A block ending in 'return e;' or a statement returns or has no value
+        _ => true,
+    }
+}
+
+// Replaces a branch value with its assignment to 'result'
+fn assign_value(expr: &mut Expr) -> bool {
+    match expr {
+        Expr::If(_) | Expr::Match(_) | Expr::Block(_) => assign_branch_values(expr),
+        Expr::Return(_) | Expr::Macro(_) => true,
+        Expr::Loop(_) | Expr::While(_) | Expr::ForLoop(_) => false,
+        value => {
+            let binding = syn::Ident::new(RESULT_BINDING, value.span());
+            *value = syn::parse2(quote_spanned!(value.span()=> #binding = #value))
+                .expect("Failed to build result assignment");
+            true
+        }
+    }
+}
 
 impl CfgBuilder {
+    // The value of a function's tail expression is assigned to the returned binding when that is
+    // 'result'. A tail with control flow, like an 'if' with a value in each branch, assigns it at
+    // the end of each branch instead.
+    pub fn handle_tail_expression(&mut self, expr: &Expr) {
+        let assigns_result = self.returned_binding.as_deref() == Some(RESULT_BINDING)
+            && !matches!(expr, Expr::Path(expr_path) if expr_path.path.is_ident(RESULT_BINDING));
+        if !assigns_result {
+            self.visit_expr(expr);
+            return;
+        }
+        match expr {
+            Expr::If(_) | Expr::Match(_) | Expr::Block(_) => {
+                let mut expr = expr.clone();
+                if !assign_branch_values(&mut expr) {
+                    log::warn!(
+                        "The value of '{}' isn't assigned to 'result', it is left unconstrained",
+                        quote!(#expr)
+                    );
+                }
+                match &expr {
+                    Expr::Block(expr_block) => self.visit_block(&expr_block.block),
+                    expr => self.visit_expr(expr),
+                }
+            }
+            Expr::Loop(_) => {
+                log::warn!(
+                    "The value a loop breaks with isn't assigned to 'result', it is left unconstrained"
+                );
+                self.visit_expr(expr);
+            }
+            Expr::While(_) | Expr::ForLoop(_) | Expr::Macro(_) | Expr::Return(_) => {
+                self.visit_expr(expr)
+            }
+            _ => {
+                self.add_call_contracts_in(expr);
+                let mut expr = expr.clone();
+                self.desugar_try_operators(&mut expr);
+                let binding = syn::Ident::new(RESULT_BINDING, expr.span());
+                let assign: Expr = syn::parse2(quote_spanned!(expr.span()=> #binding = #expr))
+                    .expect("Failed to build result assignment");
+                self.add_node(CfgNode::new_statement(
+                    quote!(#assign).to_string(),
+                    Stmt::Semi(assign, Default::default()),
+                ));
+            }
+        }
+    }
+
+    // A return jumps to the postconditions, which are connected once the whole body is built
     pub fn handle_return_statement(&mut self, expr_return: &ExprReturn) {
-        let return_expr = expr_return.expr.as_ref().map(|expr| quote!(#expr).to_string()).unwrap_or_default();
-        let return_node = self.add_node(CfgNode::new_return(return_expr, expr_return.clone()));
-        self.current_node = Some(return_node);
+        let mut expr_return = expr_return.clone();
+        if let Some(expr) = &mut expr_return.expr {
+            self.desugar_try_operators(expr);
+        }
+        let return_expr = expr_return
+            .expr
+            .as_ref()
+            .map(|expr| quote!(#expr).to_string())
+            .unwrap_or_default();
+        self.add_node(CfgNode::new_return(return_expr, expr_return.clone()));
+
+        // 'return e' before the end of the function: the postconditions hold for 'e'
+        if let (Some(binding), Some(expr)) = (self.returned_binding.clone(), &expr_return.expr) {
+            let is_binding =
+                matches!(&**expr, Expr::Path(expr_path) if expr_path.path.is_ident(&binding));
+            if !is_binding {
+                let binding = syn::Ident::new(&binding, expr.span());
+                let assign: Expr = syn::parse2(quote_spanned!(expr.span()=> #binding = #expr))
+                    .expect("Failed to build return assignment");
+                self.add_node(CfgNode::new_statement(
+                    quote!(#assign).to_string(),
+                    Stmt::Semi(assign, Default::default()),
+                ));
+            }
+        }
+        self.add_return_jump();
+    }
+
+    // Ends the current path at a return, nothing after it is reachable
+    fn add_return_jump(&mut self) {
+        if let Some(current) = self.current_node.take() {
+            self.return_nodes.push(current);
+        }
+    }
+
+    // Joins the end of the body and all returns before the postconditions
+    pub fn connect_returns(&mut self) {
+        let return_nodes: Vec<NodeIndex> = self.return_nodes.drain(..).collect();
+        if return_nodes.is_empty() {
+            return;
+        }
+        let merge_node = self.add_node(CfgNode::MergePoint);
+        for return_node in return_nodes {
+            self.add_edge_with_label(return_node, merge_node, "".to_string());
+        }
+    }
+
+    // 'expr?' returns early when 'expr' is an error. Errors aren't modeled, so each '?' branches
+    // on an unknown '__try_N' flag: the true edge returns from the function, the false edge goes
+    // on with 'expr' itself as the unwrapped value. The '?' operators are removed from 'expr'.
+    // Postconditions describe successful returns, so error returns don't lead to them.
+    pub fn desugar_try_operators(&mut self, expr: &mut Expr) {
+        let mut try_operators = TryOperators::default();
+        try_operators.visit_expr_mut(expr);
+        for operand in try_operators.0 {
+            self.try_count += 1;
+            let flag = syn::Ident::new(&format!("__try_{}", self.try_count), operand.span());
+            let cond: Expr = syn::parse2(quote!(#flag)).expect("Failed to build '?' condition");
+            let operand_str = Self::clean_up_formatting(&quote!(#operand).to_string());
+            let cond_node = self.add_node(CfgNode::new_condition(
+                format!("{}? fails", operand_str),
+                ConditionalExpr::If(Box::new(cond)),
+            ));
+
+            self.next_edge_label = Some("true".to_string());
+            self.add_node(CfgNode::Return(format!("error of {}", operand_str), None));
+
+            self.current_node = Some(cond_node);
+            self.next_edge_label = Some("false".to_string());
+        }
+    }
+}
+
+// Operands of the '?' operators of an expression, innermost first, replacing the operators
+#[derive(Default)]
+struct TryOperators(Vec<Expr>);
+
+impl VisitMut for TryOperators {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_mut::visit_expr_mut(self, expr);
+        if let Expr::Try(expr_try) = expr {
+            let operand = (*expr_try.expr).clone();
+            self.0.push(operand.clone());
+            *expr = operand;
+        }
+    }
+
+    // Closures and nested items return from themselves
+    fn visit_expr_closure_mut(&mut self, _closure: &mut ExprClosure) {}
+
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    #[test]
+    fn early_returns_end_their_path_at_the_postcondition() {
+        let conditions = CfgBuilder::verification_conditions(
+            "fn f(x: i32) -> i32 { pre!(true); if x < 0 { return 0; } let y = x + 1; \
+             post!(result > 0 || x < 0); y }",
+        )
+        .unwrap();
+        assert_eq!(
+            conditions,
+            [
+                "pre ! (true) >> ! (x < 0) >> post ! ((x + 1) > 0 || x < 0)",
+                "pre ! (true) >> (x < 0) >> post ! (0 > 0 || x < 0)",
+            ]
+        );
+    }
+
+    #[test]
+    fn results_are_the_returned_values() {
+        let source = "fn square_plus(x: i32, y: i32) -> i32 { pre!(true); \
+            if x == 0 { return y; } let z = x * x; post!(result == x * x + y); z + y }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (true) >> ! (x == 0) >> post ! (((x * x) + y) == x * x + y)",
+                "pre ! (true) >> (x == 0) >> post ! (y == x * x + y)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn branch_values_of_the_tail_are_results() {
+        let source = "fn f(x: i32) -> i32 { pre!(true); post!(result >= 0); \
+            if x > 0 { x } else { 0 - x } }
+fn g(x: i32) -> i32 { pre!(true); post!(result >= 0 && result <= 2); \
+            match x { 0 => 0, 1 => { let y = x; y } _ => if x > 0 { 2 } else { 1 } } }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap()[..2],
+            [
+                "pre ! (true) >> ! (x > 0) >> post ! ((0 - x) >= 0)",
+                "pre ! (true) >> (x > 0) >> post ! (x >= 0)",
+            ]
+        );
+        let outcomes = path_outcomes(source);
+        assert_eq!(outcomes.len(), 6);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn try_operators_go_on_with_the_unwrapped_value() {
+        let conditions = CfgBuilder::verification_conditions(
+            "fn g(x: Option<i32>, n: i32) -> Option<i32> { pre!(n > 0); let y = x?; \
+             let m = n + 1; post!(m > 1); Some(y) }",
+        )
+        .unwrap();
+        assert_eq!(
+            conditions,
+            ["pre ! (n > 0) >> ! (__try_1) >> post ! ((n + 1) > 1)"]
+        );
     }
 }
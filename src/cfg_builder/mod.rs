@@ -1,11 +1,18 @@
-pub mod builder;
-mod find_paths;
-mod handle_call;
-mod handle_condition;
-mod handle_loops;
-mod handle_macros;
-mod handle_return;
-pub mod node;
-
-pub use builder::CfgBuilder;
-pub use node::*;
+mod bool_bindings;
+pub mod builder;
+mod conjuncts;
+mod constants;
+mod contracts;
+mod find_paths;
+mod handle_call;
+mod handle_condition;
+mod handle_loops;
+mod handle_macros;
+mod handle_match;
+mod handle_return;
+pub mod node;
+mod ssa;
+
+pub use builder::{annotated_functions, CfgBuilder, ExternalMethod};
+pub(crate) use handle_loops::ForRange;
+pub use node::*;
@@ -1,101 +1,219 @@
-use proc_macro2::TokenStream;
-use quote::ToTokens;
-use syn::{Expr, ExprForLoop, ExprReturn, ItemFn, Stmt};
-
-#[derive(Clone, Debug)]
-pub enum ConditionalExpr {
-    If(Box<Expr>),
-    ForLoop(ExprForLoop),
-    While(Box<Expr>),
-}
-
-impl ConditionalExpr {
-    pub fn to_syn_expr(&self) -> &Expr {
-        match self {
-            ConditionalExpr::If(expr) | ConditionalExpr::While(expr) => expr,
-            ConditionalExpr::ForLoop(expr_for) => &expr_for.expr,
-        }
-    }
-}
-
-impl ToTokens for ConditionalExpr {
-    fn to_tokens(&self, tokens: &mut TokenStream) {
-        match self {
-            ConditionalExpr::If(expr) => expr.to_tokens(tokens),
-            ConditionalExpr::ForLoop(expr_for) => expr_for.to_tokens(tokens),
-            ConditionalExpr::While(expr) => expr.to_tokens(tokens),
-        }
-    }
-}
-
-#[derive(Clone, Debug)]
-pub enum CfgNode {
-    Function(String, Option<ItemFn>),
-    Precondition(String, Option<Expr>),
-    Postcondition(String, Option<Expr>),
-    Invariant(String, Option<Expr>),
-    Statement(String, Option<Stmt>),
-    Cutoff(String),
-    Condition(String, Option<ConditionalExpr>),
-    Return(String, Option<ExprReturn>),
-    MergePoint,
-}
-
-impl CfgNode {
-    pub fn format_dot(&self, index: usize) -> String {
-        let (label, shape) = match self {
-            CfgNode::Function(func, _) => (func.clone(), "Mdiamond"),
-            CfgNode::Precondition(pre, _) => (format!("Pre: {}", pre), "ellipse"),
-            CfgNode::Postcondition(post, _) => (format!("Post: {}", post), "ellipse"),
-            CfgNode::Invariant(inv, _) => (format!("@Inv: {}", inv), "ellipse"),
-            CfgNode::Statement(stmt, _) => (stmt.clone(), "box"),
-            CfgNode::Condition(cond, _) => (cond.clone(), "diamond"),
-            CfgNode::Cutoff(inv) => (format!("@Cutoff {}", inv), "ellipse"),
-            CfgNode::MergePoint => (String::from("Merge"), "circle"),
-            CfgNode::Return(ret, _) => (format!("return: {}", ret), "ellipse"),
-        };
-
-        format!(
-            "{} [label=\"{}\", shape={}]",
-            index,
-            self.escape_quotes_for_dot(&label),
-            shape
-        )
-    }
-
-    pub fn new_function(func_name: String, item_fn: ItemFn) -> Self {
-        CfgNode::Function(func_name, Some(item_fn))
-    }
-
-    pub fn new_precondition(pre: String, expr: Expr) -> Self {
-        CfgNode::Precondition(pre, Some(expr))
-    }
-
-    pub fn new_postcondition(post: String, expr: Expr) -> Self {
-        CfgNode::Postcondition(post, Some(expr))
-    }
-
-    pub fn new_invariant(inv: String, expr: Expr) -> Self {
-        CfgNode::Invariant(inv, Some(expr))
-    }
-
-    pub fn new_statement(stmt_str: String, stmt: Stmt) -> Self {
-        CfgNode::Statement(stmt_str, Some(stmt))
-    }
-
-    pub fn new_cutoff(inv: String) -> Self {
-        CfgNode::Cutoff(inv)
-    }
-
-    pub fn new_condition(cond: String, expr: ConditionalExpr) -> Self {
-        CfgNode::Condition(cond, Some(expr))
-    }
-
-    pub fn new_return(ret: String, expr: ExprReturn) -> Self {
-        CfgNode::Return(ret, Some(expr))
-    }
-
-    pub fn escape_quotes_for_dot(&self, input: &str) -> String {
-        input.replace("\"", "\\\"")
-    }
-}
+use proc_macro2::{Span, TokenStream};
+use quote::ToTokens;
+use serde::Serialize;
+use std::fmt;
+use syn::{spanned::Spanned, Expr, ExprForLoop, ExprReturn, ItemFn, Stmt};
+
+// Line and column (both 1-based) in the verified source file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SourceLocation {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceLocation {
+    pub fn from_span(span: Span) -> Self {
+        let start = span.start();
+        SourceLocation {
+            line: start.line,
+            column: start.column + 1, // proc-macro2 columns are 0-based
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ConditionalExpr {
+    If(Box<Expr>),
+    ForLoop(ExprForLoop),
+    While(Box<Expr>),
+    Match(Box<Expr>), // condition under which a match arm's pattern matches
+}
+
+impl ConditionalExpr {
+    pub fn to_syn_expr(&self) -> &Expr {
+        match self {
+            ConditionalExpr::If(expr)
+            | ConditionalExpr::While(expr)
+            | ConditionalExpr::Match(expr) => expr,
+            ConditionalExpr::ForLoop(expr_for) => &expr_for.expr,
+        }
+    }
+}
+
+impl ToTokens for ConditionalExpr {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        match self {
+            ConditionalExpr::If(expr) => expr.to_tokens(tokens),
+            ConditionalExpr::ForLoop(expr_for) => expr_for.to_tokens(tokens),
+            ConditionalExpr::While(expr) => expr.to_tokens(tokens),
+            ConditionalExpr::Match(expr) => expr.to_tokens(tokens),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CfgNode {
+    Function(String, Option<ItemFn>),
+    Precondition(String, Option<Expr>),
+    Postcondition(String, Option<Expr>),
+    Invariant(String, Option<Expr>),
+    Assertion(String, Option<Expr>), // 'assert!', checked where it is and assumed afterwards
+    Assumption(String, Option<Expr>), // 'assume!', assumed without being checked
+    CallPrecondition(String, Option<Expr>), // callee precondition, checked without splitting paths
+    Statement(String, Option<Stmt>),
+    Cutoff(String),
+    Condition(String, Option<ConditionalExpr>),
+    Return(String, Option<ExprReturn>),
+    MergePoint,
+}
+
+impl CfgNode {
+    pub fn format_dot(&self, index: usize) -> String {
+        self.format_dot_annotated(index, None, None)
+    }
+
+    // Node outline drawn in 'color', e.g. to show the outcome of the path it's part of, and
+    // the weakest precondition 'wp' at the node on a second line of the label
+    pub fn format_dot_annotated(
+        &self,
+        index: usize,
+        color: Option<&str>,
+        wp: Option<&str>,
+    ) -> String {
+        let (label, shape) = self.label_and_shape();
+
+        let mut label = self.escape_quotes_for_dot(&label);
+        if let Some(wp) = wp {
+            label.push_str(&format!("\\nwp: {}", self.escape_quotes_for_dot(wp)));
+        }
+        let color = color
+            .map(|color| format!(", color={}", color))
+            .unwrap_or_default();
+        format!("{} [label=\"{}\", shape={}{}]", index, label, shape, color)
+    }
+
+    // Text the node is shown with, as in the DOT graphs
+    pub fn label(&self) -> String {
+        self.label_and_shape().0
+    }
+
+    fn label_and_shape(&self) -> (String, &'static str) {
+        match self {
+            CfgNode::Function(func, _) => (func.clone(), "Mdiamond"),
+            CfgNode::Precondition(pre, _) => (format!("Pre: {}", pre), "ellipse"),
+            CfgNode::Postcondition(post, _) => (format!("Post: {}", post), "ellipse"),
+            CfgNode::Invariant(inv, _) => (format!("@Inv: {}", inv), "ellipse"),
+            CfgNode::Assertion(assertion, _) => (format!("@Assert: {}", assertion), "ellipse"),
+            CfgNode::Assumption(assumption, _) => (format!("@Assume: {}", assumption), "ellipse"),
+            CfgNode::CallPrecondition(pre, _) => (format!("@Call pre: {}", pre), "ellipse"),
+            CfgNode::Statement(stmt, _) => (stmt.clone(), "box"),
+            CfgNode::Condition(cond, _) => (cond.clone(), "diamond"),
+            CfgNode::Cutoff(inv) => (format!("@Cutoff {}", inv), "ellipse"),
+            CfgNode::MergePoint => (String::from("Merge"), "circle"),
+            CfgNode::Return(ret, _) => (format!("return: {}", ret), "ellipse"),
+        }
+    }
+
+    pub fn variant_name(&self) -> &'static str {
+        match self {
+            CfgNode::Function(_, _) => "Function",
+            CfgNode::Precondition(_, _) => "Precondition",
+            CfgNode::Postcondition(_, _) => "Postcondition",
+            CfgNode::Invariant(_, _) => "Invariant",
+            CfgNode::Assertion(_, _) => "Assertion",
+            CfgNode::Assumption(_, _) => "Assumption",
+            CfgNode::CallPrecondition(_, _) => "CallPrecondition",
+            CfgNode::Statement(_, _) => "Statement",
+            CfgNode::Cutoff(_) => "Cutoff",
+            CfgNode::Condition(_, _) => "Condition",
+            CfgNode::Return(_, _) => "Return",
+            CfgNode::MergePoint => "MergePoint",
+        }
+    }
+
+    pub fn new_function(func_name: String, item_fn: ItemFn) -> Self {
+        CfgNode::Function(func_name, Some(item_fn))
+    }
+
+    pub fn new_precondition(pre: String, expr: Expr) -> Self {
+        CfgNode::Precondition(pre, Some(expr))
+    }
+
+    pub fn new_postcondition(post: String, expr: Expr) -> Self {
+        CfgNode::Postcondition(post, Some(expr))
+    }
+
+    pub fn new_invariant(inv: String, expr: Expr) -> Self {
+        CfgNode::Invariant(inv, Some(expr))
+    }
+
+    pub fn new_assertion(assertion: String, expr: Expr) -> Self {
+        CfgNode::Assertion(assertion, Some(expr))
+    }
+
+    pub fn new_assumption(assumption: String, expr: Expr) -> Self {
+        CfgNode::Assumption(assumption, Some(expr))
+    }
+
+    pub fn new_call_precondition(pre: String, expr: Expr) -> Self {
+        CfgNode::CallPrecondition(pre, Some(expr))
+    }
+
+    pub fn new_statement(stmt_str: String, stmt: Stmt) -> Self {
+        CfgNode::Statement(stmt_str, Some(stmt))
+    }
+
+    pub fn new_cutoff(inv: String) -> Self {
+        CfgNode::Cutoff(inv)
+    }
+
+    pub fn new_condition(cond: String, expr: ConditionalExpr) -> Self {
+        CfgNode::Condition(cond, Some(expr))
+    }
+
+    pub fn new_return(ret: String, expr: ExprReturn) -> Self {
+        CfgNode::Return(ret, Some(expr))
+    }
+
+    // Location of the source code the node was built from, if it has any
+    pub fn location(&self) -> Option<SourceLocation> {
+        let span = match self {
+            CfgNode::Function(_, Some(item_fn)) => item_fn.sig.ident.span(),
+            CfgNode::Precondition(_, Some(expr))
+            | CfgNode::Postcondition(_, Some(expr))
+            | CfgNode::Invariant(_, Some(expr))
+            | CfgNode::Assertion(_, Some(expr))
+            | CfgNode::CallPrecondition(_, Some(expr)) => expr.span(),
+            CfgNode::Statement(_, Some(stmt)) => stmt.span(),
+            CfgNode::Condition(_, Some(cond)) => cond.to_syn_expr().span(),
+            CfgNode::Return(_, Some(expr_return)) => expr_return.span(),
+            _ => return None,
+        };
+        // Synthesized nodes (e.g. match arm tests) have no position in the file
+        let location = SourceLocation::from_span(span);
+        (location.line > 0).then_some(location)
+    }
+
+    // Kind of annotation, used when reporting which assertion a path has to establish
+    pub fn annotation_kind(&self) -> Option<&'static str> {
+        match self {
+            CfgNode::Precondition(_, _) => Some("Precondition"),
+            CfgNode::Postcondition(_, _) => Some("Postcondition"),
+            CfgNode::Invariant(_, _) => Some("Invariant"),
+            CfgNode::Assertion(_, _) => Some("Assertion"),
+            CfgNode::CallPrecondition(_, _) => Some("Call precondition"),
+            CfgNode::Cutoff(_) => Some("Loop cutoff"),
+            _ => None,
+        }
+    }
+
+    pub fn escape_quotes_for_dot(&self, input: &str) -> String {
+        input.replace("\"", "\\\"")
+    }
+}
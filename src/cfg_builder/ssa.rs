@@ -0,0 +1,292 @@
+use crate::cfg_builder::handle_match::is_binding;
+use proc_macro2::{Group, Ident, TokenStream, TokenTree};
+use std::collections::{HashMap, HashSet};
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    Arm, Block, Expr, ExprClosure, ExprForLoop, ExprPath, FieldValue, FnArg, Item, ItemFn, Local,
+    Macro, Pat, Stmt,
+};
+
+/// Renames shadowing 'let' bindings SSA-style before the CFG is built: in
+/// 'let x = 0; { let x = x + 1; } post!(x == 0);' the inner binding becomes 'x_1', so the
+/// substitutions of the WP calculus can't confuse it with the outer 'x' once its block ends.
+///
+/// Uses of a variable, including inside annotation macros, refer to the latest version in scope.
+/// 'post!' refers to the versions at the end of the function body and 'old!(...)' to the
+/// function parameters, so neither is renamed by its position.
+pub fn rename_shadowed_bindings(item_fn: &ItemFn) -> ItemFn {
+    let mut used_names = UsedNames::default();
+    used_names.visit_item_fn(item_fn);
+
+    let mut renamer = Renamer {
+        scopes: vec![HashMap::new()],
+        used_names: used_names.0,
+    };
+    let mut item_fn = item_fn.clone();
+    for input in item_fn.sig.inputs.iter_mut() {
+        if let FnArg::Typed(pat_type) = input {
+            renamer.bind_pattern(&mut pat_type.pat, &mut HashMap::new());
+        }
+    }
+
+    // Postconditions are renamed once the whole body is, whatever their position
+    renamer.scopes.push(HashMap::new());
+    let mut postconditions = Vec::new();
+    for (index, stmt) in item_fn.block.stmts.iter_mut().enumerate() {
+        if is_postcondition(stmt) {
+            postconditions.push(index);
+        } else {
+            renamer.visit_stmt_mut(stmt);
+        }
+    }
+    for index in postconditions {
+        renamer.visit_stmt_mut(&mut item_fn.block.stmts[index]);
+    }
+    item_fn
+}
+
+fn is_postcondition(stmt: &Stmt) -> bool {
+    matches!(stmt, Stmt::Semi(Expr::Macro(expr_macro), _) if expr_macro.mac.path.is_ident("post"))
+}
+
+// Every identifier of the function, so fresh names never clash with existing ones
+#[derive(Default)]
+struct UsedNames(HashSet<String>);
+
+impl Visit<'_> for UsedNames {
+    fn visit_ident(&mut self, ident: &Ident) {
+        self.0.insert(ident.to_string());
+    }
+
+    fn visit_macro(&mut self, mac: &Macro) {
+        collect_token_idents(mac.tokens.clone(), &mut self.0);
+        visit::visit_macro(self, mac);
+    }
+}
+
+fn collect_token_idents(tokens: TokenStream, names: &mut HashSet<String>) {
+    for tree in tokens {
+        match tree {
+            TokenTree::Ident(ident) => {
+                names.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => collect_token_idents(group.stream(), names),
+            _ => {}
+        }
+    }
+}
+
+struct Renamer {
+    scopes: Vec<HashMap<String, String>>, // source name -> current version, innermost scope last
+    used_names: HashSet<String>,
+}
+
+impl Renamer {
+    fn current_name(&self, name: &str) -> Option<&String> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    fn rename(&self, ident: &mut Ident) {
+        if let Some(current) = self.current_name(&ident.to_string()) {
+            if *ident != current {
+                *ident = Ident::new(current, ident.span());
+            }
+        }
+    }
+
+    // A binding of a name already in scope gets the next free 'name_N'
+    fn bind(&mut self, ident: &mut Ident, pattern_names: &mut HashMap<String, String>) {
+        let name = ident.to_string();
+        let version = match pattern_names.get(&name) {
+            // Alternatives of an or-pattern bind the same variable
+            Some(version) => version.clone(),
+            None if self.current_name(&name).is_some() => {
+                let version = (1..)
+                    .map(|n| format!("{}_{}", name, n))
+                    .find(|candidate| !self.used_names.contains(candidate))
+                    .unwrap();
+                self.used_names.insert(version.clone());
+                version
+            }
+            None => name.clone(),
+        };
+        pattern_names.insert(name.clone(), version.clone());
+        self.scopes
+            .last_mut()
+            .unwrap()
+            .insert(name, version.clone());
+        *ident = Ident::new(&version, ident.span());
+    }
+
+    fn bind_pattern(&mut self, pat: &mut Pat, pattern_names: &mut HashMap<String, String>) {
+        match pat {
+            Pat::Ident(pat_ident) => {
+                if let Some((_, subpat)) = &mut pat_ident.subpat {
+                    self.bind_pattern(subpat, pattern_names);
+                }
+                if is_binding(&pat_ident.ident) {
+                    self.bind(&mut pat_ident.ident, pattern_names);
+                }
+            }
+            Pat::Tuple(pat_tuple) => {
+                for elem in pat_tuple.elems.iter_mut() {
+                    self.bind_pattern(elem, pattern_names);
+                }
+            }
+            Pat::TupleStruct(pat_tuple_struct) => {
+                for elem in pat_tuple_struct.pat.elems.iter_mut() {
+                    self.bind_pattern(elem, pattern_names);
+                }
+            }
+            Pat::Struct(pat_struct) => {
+                for field in pat_struct.fields.iter_mut() {
+                    self.bind_pattern(&mut field.pat, pattern_names);
+                    // A renamed shorthand field 'Point { x }' becomes 'Point { x: x_1 }'
+                    if field.colon_token.is_none() {
+                        field.colon_token = Some(Default::default());
+                    }
+                }
+            }
+            Pat::Slice(pat_slice) => {
+                for elem in pat_slice.elems.iter_mut() {
+                    self.bind_pattern(elem, pattern_names);
+                }
+            }
+            Pat::Or(pat_or) => {
+                for case in pat_or.cases.iter_mut() {
+                    self.bind_pattern(case, pattern_names);
+                }
+            }
+            Pat::Reference(pat_reference) => {
+                self.bind_pattern(&mut pat_reference.pat, pattern_names)
+            }
+            Pat::Box(pat_box) => self.bind_pattern(&mut pat_box.pat, pattern_names),
+            Pat::Type(pat_type) => self.bind_pattern(&mut pat_type.pat, pattern_names),
+            _ => {}
+        }
+    }
+
+    // Macro arguments are plain tokens: rename identifiers that aren't macro names, fields or
+    // methods, leaving 'old!(...)' and the variables bound by quantifiers alone
+    fn rename_tokens(&mut self, tokens: TokenStream) -> TokenStream {
+        let trees: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut renamed = Vec::with_capacity(trees.len());
+        let mut i = 0;
+        while i < trees.len() {
+            match &trees[i] {
+                TokenTree::Ident(ident) => {
+                    let is_macro =
+                        matches!(trees.get(i + 1), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+                    if let (true, Some(TokenTree::Group(group))) = (is_macro, trees.get(i + 2)) {
+                        let stream = if ident == "old" {
+                            group.stream()
+                        } else if ident == "forall" || ident == "exists" {
+                            self.rename_quantifier(group.stream())
+                        } else {
+                            self.rename_tokens(group.stream())
+                        };
+                        renamed.push(trees[i].clone());
+                        renamed.push(trees[i + 1].clone());
+                        renamed.push(TokenTree::Group(with_stream(group, stream)));
+                        i += 3;
+                        continue;
+                    }
+                    let after_dot =
+                        i > 0 && matches!(&trees[i - 1], TokenTree::Punct(p) if p.as_char() == '.');
+                    let mut ident = ident.clone();
+                    if !after_dot {
+                        self.rename(&mut ident);
+                    }
+                    renamed.push(TokenTree::Ident(ident));
+                }
+                TokenTree::Group(group) => {
+                    let stream = self.rename_tokens(group.stream());
+                    renamed.push(TokenTree::Group(with_stream(group, stream)));
+                }
+                other => renamed.push(other.clone()),
+            }
+            i += 1;
+        }
+        renamed.into_iter().collect()
+    }
+
+    // 'forall!(k, body)': 'k' shadows any variable named 'k' in the body
+    fn rename_quantifier(&mut self, tokens: TokenStream) -> TokenStream {
+        let mut trees = tokens.into_iter();
+        let Some(TokenTree::Ident(bound)) = trees.next() else {
+            return TokenStream::new();
+        };
+        let bound_name = bound.to_string();
+        self.scopes
+            .push(HashMap::from([(bound_name.clone(), bound_name)]));
+        let body = self.rename_tokens(trees.collect());
+        self.scopes.pop();
+        std::iter::once(TokenTree::Ident(bound))
+            .chain(body)
+            .collect()
+    }
+}
+
+fn with_stream(group: &Group, stream: TokenStream) -> Group {
+    let mut renamed = Group::new(group.delimiter(), stream);
+    renamed.set_span(group.span());
+    renamed
+}
+
+impl VisitMut for Renamer {
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        self.scopes.push(HashMap::new());
+        visit_mut::visit_block_mut(self, block);
+        self.scopes.pop();
+    }
+
+    // The initializer still sees the previous version: 'let x = x + 1;' is 'let x_1 = x + 1;'
+    fn visit_local_mut(&mut self, local: &mut Local) {
+        if let Some((_, init)) = &mut local.init {
+            self.visit_expr_mut(init);
+        }
+        self.bind_pattern(&mut local.pat, &mut HashMap::new());
+    }
+
+    fn visit_expr_for_loop_mut(&mut self, expr_for: &mut ExprForLoop) {
+        self.visit_expr_mut(&mut expr_for.expr);
+        self.scopes.push(HashMap::new());
+        self.bind_pattern(&mut expr_for.pat, &mut HashMap::new());
+        self.visit_block_mut(&mut expr_for.body);
+        self.scopes.pop();
+    }
+
+    fn visit_arm_mut(&mut self, arm: &mut Arm) {
+        self.scopes.push(HashMap::new());
+        self.bind_pattern(&mut arm.pat, &mut HashMap::new());
+        if let Some((_, guard)) = &mut arm.guard {
+            self.visit_expr_mut(guard);
+        }
+        self.visit_expr_mut(&mut arm.body);
+        self.scopes.pop();
+    }
+
+    fn visit_expr_path_mut(&mut self, expr_path: &mut ExprPath) {
+        if expr_path.qself.is_none() && expr_path.path.segments.len() == 1 {
+            self.rename(&mut expr_path.path.segments[0].ident);
+        }
+    }
+
+    // A renamed shorthand field 'Point { x }' becomes 'Point { x: x_1 }'
+    fn visit_field_value_mut(&mut self, field_value: &mut FieldValue) {
+        self.visit_expr_mut(&mut field_value.expr);
+        if field_value.colon_token.is_none() {
+            field_value.colon_token = Some(Default::default());
+        }
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        mac.tokens = self.rename_tokens(mac.tokens.clone());
+    }
+
+    // Closures and nested items have scopes of their own and aren't part of the CFG
+    fn visit_expr_closure_mut(&mut self, _closure: &mut ExprClosure) {}
+
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+}
@@ -5,7 +5,14 @@ pub mod wp_calculus;
 pub use cfg_builder::*;
 pub use verifier::*;
 
+use petgraph::graph::NodeIndex;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
+use std::time::Instant;
+use syn::spanned::Spanned;
 
 use std::fs::File;
 use std::io::Write;
@@ -26,6 +33,12 @@ macro_rules! invariant {
     ($($t:tt)*) => {{}};
 }
 
+// Assumed by the verifier without being checked, e.g. a guarantee of external code
+#[macro_export]
+macro_rules! assume {
+    ($($t:tt)*) => {{}};
+}
+
 #[macro_export]
 macro_rules! build_cfg {
     ($($t:tt)*) => {{}};
@@ -36,55 +49,899 @@ macro_rules! old {
     ($($t:tt)*) => {{}};
 }
 
+#[macro_export]
+macro_rules! forall {
+    ($($t:tt)*) => {{}};
+}
+
+#[macro_export]
+macro_rules! exists {
+    ($($t:tt)*) => {{}};
+}
+
+// 'range_contains!(x, a..b)' in annotations: 'x' lies in the range, '..=' including 'b'
+#[macro_export]
+macro_rules! range_contains {
+    ($($t:tt)*) => {{}};
+}
+
+// Directory the DOT files are written to by default, relative to the working directory
+pub const DEFAULT_OUT_DIR: &str = "secrust-graphs";
+
+// Options controlling a verification run
+#[derive(Debug, Clone)]
+pub struct VerificationOptions {
+    pub generate_dot: bool, // write DOT files for the CFG and its basic paths
+    pub dot_wp: bool,       // label the nodes of the basic path DOT files with their WP
+    pub verbose: bool,      // print implications, solver results and counterexamples
+    pub conditions_path: Option<PathBuf>, // external conditions file, defaults to DEFAULT_CONDITIONS_PATH
+    pub timeout_ms: u32,                  // solver timeout per path
+    pub jobs: usize,                      // paths verified in parallel, 0 for one per core
+    pub smt2_dir: Option<PathBuf>,        // write each path's condition as 'path_N.smt2' here
+    pub function: Option<String>,         // only verify the function with this name
+    pub int_width: Option<IntWidth>,      // fixed-width integers instead of unbounded ones
+    pub external_methods: Vec<ExternalMethod>, // added to the conditions file, replacing same names
+    pub de_morgan: bool,                  // push the negations of false branch conditions inwards
+    pub z3_params: Vec<(String, String)>, // Z3 global parameters, e.g. ("smt.random_seed", "42")
+    pub extra_postconditions: Vec<String>, // checked like a 'post!' of every verified function
+    pub out_dir: PathBuf,                 // DOT files are written to '<out_dir>/<file stem>'
+    pub cache_path: Option<PathBuf>, // reuse and update the outcomes of unchanged functions here
+    pub proof: bool,                 // keep Z3's proof of each valid path in the report
+}
+
+impl Default for VerificationOptions {
+    fn default() -> Self {
+        VerificationOptions {
+            generate_dot: false,
+            dot_wp: false,
+            verbose: false,
+            conditions_path: None,
+            timeout_ms: DEFAULT_TIMEOUT_MS,
+            jobs: 0,
+            smt2_dir: None,
+            function: None,
+            int_width: None,
+            external_methods: Vec::new(),
+            de_morgan: false,
+            z3_params: Vec::new(),
+            extra_postconditions: Vec::new(),
+            out_dir: PathBuf::from(DEFAULT_OUT_DIR),
+            cache_path: None,
+            proof: false,
+        }
+    }
+}
+
 pub fn run_verification(
     file_path: &PathBuf,
-    generate_dot: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    println!("file path: {:?}", file_path);
-    let content = std::fs::read_to_string(&file_path)?;
+    options: &VerificationOptions,
+) -> Result<VerificationReport, Box<dyn std::error::Error>> {
+    log::debug!("file path: {:?}", file_path);
+    let content = std::fs::read_to_string(file_path)?;
+    let file_stem = file_path.file_stem().unwrap(); // Get the file name without extension
+    verify_source(&content, file_stem, Some(file_path), options)
+}
+
+// Verify the '.rs' files of a directory and its subdirectories, in path order. Files without
+// annotated functions (or without the one of 'options.function') are skipped; a file that
+// can't be read or verified, e.g. with a syntax error, is reported and the others are still
+// verified. SMT-LIB2 files are written to a subdirectory of 'options.smt2_dir' per file, and
+// DOT files to 'options.out_dir' following the layout of 'dir'.
+pub fn run_verification_dir(
+    dir: &Path,
+    options: &VerificationOptions,
+) -> Result<DirectoryReport, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    collect_rust_files(dir, &mut files)?;
+    files.sort();
+
+    let mut report = DirectoryReport::default();
+    let mut all_annotated = Vec::new();
+    for file in files {
+        // An unreadable file, e.g. not valid UTF-8, is reported like one that doesn't parse
+        let content = match std::fs::read_to_string(&file) {
+            Ok(content) => content,
+            Err(e) => {
+                let error = format!("Cannot read {}: {}", file.display(), e);
+                report.files.push(FileReport {
+                    file,
+                    report: VerificationReport::default(),
+                    error: Some(error),
+                });
+                continue;
+            }
+        };
+        let file_stem = file.file_stem().unwrap();
+        let relative = file.strip_prefix(dir).unwrap_or(&file).with_extension("");
+        let file_options = VerificationOptions {
+            smt2_dir: options
+                .smt2_dir
+                .as_ref()
+                .map(|smt2_dir| smt2_dir.join(&relative)),
+            out_dir: match relative.parent() {
+                Some(parent) => options.out_dir.join(parent),
+                None => options.out_dir.clone(),
+            },
+            ..options.clone()
+        };
+        let result = parse_source(&content, Some(&file)).and_then(|ast| {
+            let annotated = annotated_functions(&ast);
+            let verified = match &options.function {
+                Some(function) => annotated.contains(function),
+                None => !annotated.is_empty(),
+            };
+            all_annotated.extend(annotated);
+            verified
+                .then(|| verify_source(&content, file_stem, Some(&file), &file_options))
+                .transpose()
+        });
+        match result {
+            Ok(None) => log::debug!("Skipping {:?}: no annotated function", file),
+            Ok(Some(file_report)) => report.files.push(FileReport {
+                file,
+                report: file_report,
+                error: None,
+            }),
+            Err(e) => report.files.push(FileReport {
+                file,
+                report: VerificationReport::default(),
+                error: Some(e.to_string()),
+            }),
+        }
+    }
+    // Like for a single file, a function no file has is an error rather than nothing verified
+    if let Some(function) = &options.function {
+        if report.files.is_empty() {
+            return Err(format!(
+                "Function '{}' not found among the annotated functions: {}",
+                function,
+                all_annotated.join(", ")
+            )
+            .into());
+        }
+    }
+    Ok(report)
+}
+
+// Build output ('target') and hidden directories like '.git' hold no sources to verify
+fn collect_rust_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name == "target" || name.starts_with('.') {
+                continue;
+            }
+            collect_rust_files(&path, files)?;
+        } else if path.extension() == Some(OsStr::new("rs")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Verify Rust source code given as a string, without reading it from disk.
+// DOT files, if enabled, are written to '<options.out_dir>/source'.
+pub fn run_verification_str(
+    source: &str,
+    options: &VerificationOptions,
+) -> Result<VerificationReport, Box<dyn std::error::Error>> {
+    verify_source(source, OsStr::new("source"), None, options)
+}
+
+// Verification conditions of a file's basic paths, in path order, without solving them
+pub fn emit_verification_conditions(
+    file_path: &PathBuf,
+    options: &VerificationOptions,
+) -> Result<Vec<VerificationCondition>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(file_path)?;
+    let file_stem = file_path.file_stem().unwrap();
+    Ok(prepare_source(&content, file_stem, Some(file_path), options)?.conditions)
+}
 
+// CFG of a source with its basic paths and their verification conditions
+struct PreparedSource {
+    builder: CfgBuilder,
+    basic_paths: Vec<Vec<NodeIndex>>,
+    conditions: Vec<VerificationCondition>,
+    condition_paths: Vec<usize>, // index of the basic path of each condition
+}
+
+// Text listing of a file's CFG, before basic paths are generated or anything is solved
+pub fn dump_cfg(
+    file_path: &PathBuf,
+    options: &VerificationOptions,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(file_path)?;
+    let file_stem = file_path.file_stem().unwrap();
+    Ok(build_source_cfg(&content, file_stem, Some(file_path), options)?.dump())
+}
+
+// Parses a source, reporting a syntax error with the file and the line and column it is at
+fn parse_source(
+    source: &str,
+    file: Option<&Path>,
+) -> Result<syn::File, Box<dyn std::error::Error>> {
+    syn::parse_file(source).map_err(|e| {
+        let start = e.span().start();
+        let location = format!("line {}, column {}", start.line, start.column + 1);
+        // syn says no more about tokens it can't even split
+        let e = match e.to_string().as_str() {
+            "lex error" => "unbalanced delimiter or invalid token".to_string(),
+            message => message.to_string(),
+        };
+        match file {
+            Some(file) => format!("Syntax error in {} at {}: {}", file.display(), location, e),
+            None => format!("Syntax error at {}: {}", location, e),
+        }
+        .into()
+    })
+}
+
+fn build_source_cfg(
+    source: &str,
+    name: &OsStr,
+    file: Option<&Path>,
+    options: &VerificationOptions,
+) -> Result<CfgBuilder, Box<dyn std::error::Error>> {
     // parse file and build ast
-    let ast = syn::parse_file(&content)?;
-    println!("AST successfully parsed for file {:?}", file_path);
+    let ast = parse_source(source, file)?;
+    log::debug!("AST successfully parsed for {:?}", name);
 
     // visit ast
-    let mut builder = CfgBuilder::new();
+    let mut builder = match &options.conditions_path {
+        Some(conditions_path) => CfgBuilder::with_config_path(conditions_path)?,
+        None => CfgBuilder::with_default_config()?,
+    };
+    for external_method in &options.external_methods {
+        builder.add_external_method(external_method.clone());
+    }
 
+    builder.function_filter = options.function.clone();
+    builder.de_morgan = options.de_morgan;
+    for postcondition in &options.extra_postconditions {
+        let cond = syn::parse_str(postcondition)
+            .map_err(|e| format!("Invalid postcondition '{}': {}", postcondition, e))?;
+        builder.extra_postconditions.push(cond);
+    }
     builder.build_cfg(&ast);
 
+    if let Some(function) = &options.function {
+        if !builder.annotated_functions.contains(function) {
+            return Err(format!(
+                "Function '{}' not found among the annotated functions: {}",
+                function,
+                builder.annotated_functions.join(", ")
+            )
+            .into());
+        }
+    }
+
+    Ok(builder)
+}
+
+fn prepare_source(
+    source: &str,
+    name: &OsStr,
+    file: Option<&Path>,
+    options: &VerificationOptions,
+) -> Result<PreparedSource, Box<dyn std::error::Error>> {
+    let mut builder = build_source_cfg(source, name, file, options)?;
     let basic_paths = builder.generate_basic_paths();
 
-    let final_implication = builder.apply_wp_calculus(&basic_paths);
-    for (i, implication) in final_implication.iter().enumerate() {
-        println!("---------");
-        println!("Final implication for Path {}: {}", i + 1, implication);
-        verifier::verify_str_implication(implication);
-        println!("Verification completed for {:?}", implication);
-        println!("---------");
-        println!("");
+    // Keep track of the path each implication comes from to report its target annotation
+    // and kind
+    let mut conditions = Vec::new();
+    let mut condition_paths = Vec::new();
+    for (path_index, path) in basic_paths.iter().enumerate() {
+        let target = builder.path_target(path);
+        let kind = builder.path_kind(path);
+        let source = builder.path_source(path);
+        let function = source.as_ref().map(|source| &source.function);
+        let function_bool_vars = function.and_then(|function| builder.bool_vars.get(function));
+        let function_real_vars = function.and_then(|function| builder.real_vars.get(function));
+        for implication in builder.apply_wp_calculus(std::slice::from_ref(path)) {
+            // Only the typed variables the implication still refers to
+            let mentioned = |vars: Option<&HashSet<String>>| {
+                let mut mentioned: Vec<String> = vars
+                    .into_iter()
+                    .flatten()
+                    .filter(|name| {
+                        implication
+                            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                            .any(|word| word == name.as_str())
+                    })
+                    .cloned()
+                    .collect();
+                mentioned.sort();
+                mentioned
+            };
+            let bool_vars = mentioned(function_bool_vars);
+            let real_vars = mentioned(function_real_vars);
+            conditions.push(VerificationCondition {
+                implication,
+                target: target.clone(),
+                kind,
+                source: source.clone(),
+                bool_vars,
+                real_vars,
+            });
+            condition_paths.push(path_index);
+        }
     }
 
-    if generate_dot {
+    Ok(PreparedSource {
+        builder,
+        basic_paths,
+        conditions,
+        condition_paths,
+    })
+}
+
+// Conditions are translated recursively, and long chains like 'a - b - c - ...' in
+// substituted conditions nest deeper than the default 2 MiB of a worker thread
+const WORKER_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+// 'name' is used for the DOT output directory and file, and 'file' as the key of the
+// source's functions in the cache, without which the cache isn't used
+fn verify_source(
+    source: &str,
+    name: &OsStr,
+    file: Option<&Path>,
+    options: &VerificationOptions,
+) -> Result<VerificationReport, Box<dyn std::error::Error>> {
+    let verbose = options.verbose;
+    let PreparedSource {
+        builder,
+        basic_paths,
+        conditions,
+        condition_paths,
+    } = prepare_source(source, name, file, options)?;
+    // Set for this run only, until the end of the function
+    let _z3_params = verifier::set_z3_params(&options.z3_params)?;
+
+    // Functions whose conditions are unchanged since a cached run reuse its outcomes
+    let mut outcomes: Vec<Option<VerifyOutcome>> = vec![None; conditions.len()];
+    let cache_file = options
+        .cache_path
+        .as_ref()
+        .zip(file.map(|file| std::fs::canonicalize(file).unwrap_or(file.to_path_buf())));
+    let mut cache = cache_file
+        .as_ref()
+        .map(|(cache_path, _)| VerificationCache::load(cache_path));
+    let mut function_conditions: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, condition) in conditions.iter().enumerate() {
+        if let Some(source) = &condition.source {
+            function_conditions
+                .entry(&source.function)
+                .or_default()
+                .push(i);
+        }
+    }
+    let function_hashes: BTreeMap<&str, String> = function_conditions
+        .iter()
+        .map(|(&function, indices)| {
+            let function_conditions: Vec<&VerificationCondition> =
+                indices.iter().map(|&i| &conditions[i]).collect();
+            let hash = conditions_hash(&function_conditions, options.int_width, &options.z3_params);
+            (function, hash)
+        })
+        .collect();
+    // Cached outcomes come without proofs, so paths are verified again when asked for them
+    if let (Some(cache), Some((_, file)), false) = (&cache, &cache_file, options.proof) {
+        let file = file.to_string_lossy();
+        for (function, indices) in &function_conditions {
+            let cached = cache.lookup(&file, function, &function_hashes[function]);
+            if let Some(cached) = cached.filter(|cached| cached.len() == indices.len()) {
+                log::debug!("Reusing the cached results of fn {}", function);
+                for (&i, outcome) in indices.iter().zip(cached) {
+                    outcomes[i] = Some(outcome);
+                }
+            }
+        }
+    }
+    let pending: Vec<usize> = (0..conditions.len())
+        .filter(|&i| outcomes[i].is_none())
+        .collect();
+    let verified_now: HashSet<usize> = pending.iter().copied().collect();
+    if cache.is_some() && pending.len() < conditions.len() {
+        log::info!(
+            "Reused the cached results of {} of {} paths",
+            conditions.len() - pending.len(),
+            conditions.len()
+        );
+    }
+
+    // Verify paths in parallel. Z3 contexts aren't 'Sync', so each worker gets a contiguous
+    // chunk of paths and verifies them with one context and solver of its own. Verbose output
+    // is printed as paths are verified, so it uses one worker to keep the paths in order.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(if verbose { 1 } else { options.jobs })
+        .stack_size(WORKER_STACK_SIZE)
+        .build()?;
+    let chunk_size = pending.len().div_ceil(pool.current_num_threads()).max(1);
+    let verified: Vec<(usize, VerifyOutcome, Option<String>, f64)> = pool.install(|| {
+        pending
+            .par_chunks(chunk_size)
+            .flat_map_iter(|chunk| {
+                let cfg = if options.proof {
+                    verifier::proof_config()
+                } else {
+                    z3::Config::new()
+                };
+                let ctx = z3::Context::new(&cfg);
+                let mut solver = verifier::new_solver(&ctx, options.timeout_ms);
+                chunk
+                    .iter()
+                    .map(|&i| {
+                        let condition = &conditions[i];
+                        if verbose {
+                            println!("---------");
+                            // Tie the path back to the source, files can have several functions
+                            match &condition.source {
+                                Some(source) => println!(
+                                    "Final implication for Path {} ({}): {}",
+                                    i + 1,
+                                    source,
+                                    condition.implication
+                                ),
+                                None => println!(
+                                    "Final implication for Path {}: {}",
+                                    i + 1,
+                                    condition.implication
+                                ),
+                            }
+                        }
+                        // A path that can't be translated is reported without aborting the others,
+                        // and so is one that panics, with a fresh solver for the next paths
+                        let start = Instant::now();
+                        let (outcome, proof) = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            if options.proof {
+                                verifier::verify_str_implication_with_proof(
+                                    &ctx,
+                                    &mut solver,
+                                    &condition.implication,
+                                    options.int_width,
+                                    &condition.bool_vars,
+                                    &condition.real_vars,
+                                    verbose,
+                                )
+                            } else {
+                                verifier::verify_str_implication_with(
+                                    &ctx,
+                                    &mut solver,
+                                    &condition.implication,
+                                    options.int_width,
+                                    &condition.bool_vars,
+                                    &condition.real_vars,
+                                    verbose,
+                                )
+                                .map(|outcome| (outcome, None))
+                            }
+                        }))
+                        .unwrap_or_else(|panic| {
+                            solver = verifier::new_solver(&ctx, options.timeout_ms);
+                            Err(ParseError::Internal(panic_message(panic.as_ref())))
+                        })
+                        .unwrap_or_else(|e| (VerifyOutcome::Error(e), None));
+                        let time_ms = start.elapsed().as_secs_f64() * 1000.0;
+                        if verbose {
+                            println!("Verification completed for {:?}", condition.implication);
+                            println!("---------");
+                            println!();
+                        }
+                        (i, outcome, proof, time_ms)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+    let mut proofs: Vec<Option<String>> = vec![None; conditions.len()];
+    let mut times_ms: Vec<Option<f64>> = vec![None; conditions.len()];
+    for (i, outcome, proof, time_ms) in verified {
+        outcomes[i] = Some(outcome);
+        proofs[i] = proof;
+        times_ms[i] = Some(time_ms);
+    }
+    let outcomes: Vec<VerifyOutcome> = outcomes.into_iter().flatten().collect();
+
+    // Keep the outcomes of the functions verified this time for the next run
+    if let (Some(cache), Some((cache_path, file))) = (&mut cache, &cache_file) {
+        let file = file.to_string_lossy();
+        for (function, indices) in &function_conditions {
+            if indices.iter().any(|i| verified_now.contains(i)) {
+                let function_outcomes: Vec<VerifyOutcome> =
+                    indices.iter().map(|&i| outcomes[i].clone()).collect();
+                let hash = function_hashes[function].clone();
+                cache.store(&file, function, hash, &function_outcomes);
+            }
+        }
+        if let Err(e) = cache.save(cache_path) {
+            log::warn!("Failed to save the cache {}: {}", cache_path.display(), e);
+        }
+    }
+
+    let paths = conditions
+        .iter()
+        .zip(outcomes)
+        .zip(proofs)
+        .zip(times_ms)
+        .map(|(((condition, outcome), proof), time_ms)| PathResult {
+            implication: condition.implication.clone(),
+            outcome,
+            target: condition.target.clone(),
+            kind: condition.kind,
+            source: condition.source.clone(),
+            proof,
+            time_ms,
+        })
+        .collect();
+    let mut report = VerificationReport {
+        paths,
+        unsatisfiable_preconditions: unsatisfiable_preconditions(&builder, options),
+        dot_dir: None,
+    };
+
+    if let Some(smt2_dir) = &options.smt2_dir {
+        // Save each path condition as a standalone SMT-LIB2 script, numbered like the reported paths
+        std::fs::create_dir_all(smt2_dir)?;
+        // Translated on a worker, for its larger stack
+        pool.install(|| -> std::io::Result<()> {
+            for (i, condition) in conditions.iter().enumerate() {
+                let smt2_file_path = smt2_dir.join(format!("path_{}.smt2", i + 1));
+                match verifier::str_implication_to_smt2(
+                    &condition.implication,
+                    options.int_width,
+                    &condition.bool_vars,
+                    &condition.real_vars,
+                ) {
+                    Ok(smt2) => std::fs::write(
+                        &smt2_file_path,
+                        format!("; {}\n{}", condition.implication, smt2),
+                    )?,
+                    Err(e) => log::warn!("Skipping SMT-LIB2 export of path {}: {}", i + 1, e),
+                }
+            }
+            Ok(())
+        })?;
+        log::info!("SMT-LIB2 files saved in: {:?}", smt2_dir);
+    }
+
+    if options.generate_dot || options.dot_wp {
         // Save the DOT file and basic paths in the directory named after the input file
-        let output_base_path = Path::new("src/graphs");
-        let file_stem = file_path.file_stem().unwrap(); // Get the file name without extension
-        let output_dir = output_base_path.join(file_stem); // Create directory path as "src/graphs/filename"
+        let output_dir = options.out_dir.join(name); // Create directory path as "<out_dir>/filename"
 
-        // Generate the DOT format for the entire CFG
+        // Generate the DOT format for the entire CFG, and with all basic paths highlighted
         let dot_format = builder.to_dot();
+        let paths_dot_format = builder.to_dot_with_paths(&basic_paths);
+
+        // Save all basic paths inside the output directory, colored by their outcome
+        let mut path_outcomes = vec![None; basic_paths.len()];
+        for (&path_index, path_result) in condition_paths.iter().zip(&report.paths) {
+            path_outcomes[path_index] = Some(path_result.outcome.clone());
+        }
+        let dot_file_path = output_dir.join(format!("{}.dot", name.to_string_lossy()));
+        (|| -> std::io::Result<()> {
+            builder.write_annotated_paths_to_dot_files(
+                basic_paths,
+                &path_outcomes,
+                options.dot_wp,
+                &output_dir,
+            )?;
 
-        // Save all basic paths inside the output directory
-        builder.write_paths_to_dot_files(basic_paths, &output_dir);
+            // Save the main DOT file in the same directory
+            let mut dot_file = File::create(&dot_file_path)?;
+            dot_file.write_all(dot_format.as_bytes())?;
 
-        // Save the main DOT file in the same directory
-        let dot_file_path = output_dir.join(format!("{}.dot", file_stem.to_string_lossy()));
-        let mut dot_file = File::create(&dot_file_path).expect("Unable to create DOT file");
-        dot_file
-            .write_all(dot_format.as_bytes())
-            .expect("Unable to write to DOT file");
+            let paths_dot_file_path = output_dir.join("all_paths.dot");
+            std::fs::write(&paths_dot_file_path, paths_dot_format)
+        })()
+        .map_err(|e| format!("Cannot write DOT files to {}: {}", output_dir.display(), e))?;
 
-        println!("DOT graph saved as: {:?}", dot_file_path);
+        log::info!("DOT graph saved as: {:?}", dot_file_path);
+        report.dot_dir = Some(std::fs::canonicalize(&output_dir).unwrap_or(output_dir));
     }
 
-    Ok(())
+    Ok(report)
+}
+
+// Functions whose preconditions no values satisfy: everything follows from them, so their
+// paths would verify without proving anything. Preconditions Z3 can't check are left out.
+fn unsatisfiable_preconditions(
+    builder: &CfgBuilder,
+    options: &VerificationOptions,
+) -> Vec<UnsatisfiablePrecondition> {
+    let mut functions: Vec<&String> = builder
+        .contracts
+        .keys()
+        .filter(|name| {
+            options
+                .function
+                .as_ref()
+                .is_none_or(|function| function == *name)
+        })
+        .collect();
+    functions.sort();
+
+    let cfg = z3::Config::new();
+    let ctx = z3::Context::new(&cfg);
+    let mut solver = verifier::new_solver(&ctx, options.timeout_ms);
+    let mut unsatisfiable = Vec::new();
+    for function in functions {
+        let preconditions = &builder.contracts[function].preconditions;
+        let Some(first) = preconditions.first() else {
+            continue;
+        };
+        let precondition: syn::Expr = syn::parse_quote!(#((#preconditions))&&*);
+        let precondition = builder.substitute_constants(&precondition, &builder.constants);
+        let bool_vars: Vec<String> = builder
+            .bool_vars
+            .get(function)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let real_vars: Vec<String> = builder
+            .real_vars
+            .get(function)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            verifier::check_expr_satisfiable_with(
+                &ctx,
+                &mut solver,
+                &precondition,
+                options.int_width,
+                &bool_vars,
+                &real_vars,
+            )
+        }));
+        match result {
+            Ok(Ok(z3::SatResult::Unsat)) => {
+                let location = SourceLocation::from_span(first.span());
+                unsatisfiable.push(UnsatisfiablePrecondition {
+                    function: function.clone(),
+                    precondition: precondition_label(preconditions),
+                    location: (location.line > 0).then_some(location),
+                });
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::debug!("Preconditions of '{}' not checked: {}", function, e),
+            Err(_) => {
+                log::debug!("Preconditions of '{}' not checked", function);
+                solver = verifier::new_solver(&ctx, options.timeout_ms);
+            }
+        }
+    }
+    unsatisfiable
+}
+
+// "x > 0" for one precondition, "(x > 0) && (x < 10)" for several
+fn precondition_label(preconditions: &[syn::Expr]) -> String {
+    let labels: Vec<String> = preconditions
+        .iter()
+        .map(|pre| CfgBuilder::clean_up_formatting(&quote::quote!(#pre).to_string()))
+        .collect();
+    match labels.as_slice() {
+        [label] => label.clone(),
+        _ => format!("({})", labels.join(") && (")),
+    }
+}
+
+// Message a panic was raised with, as given to 'panic!' or 'expect'
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Outcomes of the paths of a source verified with the default options, in path order, for
+// the tests of the modules
+#[cfg(test)]
+pub(crate) fn path_outcomes(source: &str) -> Vec<VerifyOutcome> {
+    run_verification_str(source, &VerificationOptions::default())
+        .expect("Failed to verify the test source")
+        .paths
+        .into_iter()
+        .map(|path| path.outcome)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        run_verification_dir, run_verification_str, PathKind, VerificationOptions, VerifyOutcome,
+    };
+
+    #[test]
+    fn sources_are_verified_without_a_file() {
+        let report = run_verification_str(
+            "fn f(x: i32) -> i32 { pre!(x > 0); let y = x + 1; post!(y > 1); y }\n\
+             fn g(x: i32) -> i32 { pre!(x > 0); let y = x - 1; post!(y > 0); y }",
+            &VerificationOptions::default(),
+        )
+        .unwrap();
+        let summary = report.summary();
+        assert_eq!((summary.valid, summary.invalid), (1, 1));
+        assert!(report.dot_dir.is_none());
+        assert!(run_verification_str("fn f( {", &VerificationOptions::default()).is_err());
+    }
+
+    #[test]
+    fn dot_files_are_written_or_their_error_reported() {
+        let dir = std::env::temp_dir().join(format!("secrust-dot-{}", std::process::id()));
+        let source = "fn f(x: i32) -> i32 { pre!(x > 0); post!(x > 0); x }";
+        let options = |out_dir: std::path::PathBuf| VerificationOptions {
+            generate_dot: true,
+            out_dir,
+            ..VerificationOptions::default()
+        };
+        let written = run_verification_str(source, &options(dir.join("graphs"))).unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+        let unwritable = run_verification_str(source, &options(dir.join("file"))).map(|_| ());
+        let dot_dir = written.dot_dir.unwrap();
+        let all_paths = dot_dir.join("all_paths.dot").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(all_paths);
+        let error = unwritable.unwrap_err().to_string();
+        assert!(
+            error.starts_with(&format!(
+                "Cannot write DOT files to {}: ",
+                dir.join("file").join("source").display()
+            )),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn paths_name_their_function_and_lines() {
+        let report = run_verification_str(
+            "fn f(x: i32) -> i32 { pre!(x > 0); let y = x + 1; post!(y > 1); y }\n\
+             fn g(x: i32) -> i32 {\n\
+                 pre!(x > 0);\n\
+                 let y = x - 1;\n\
+                 post!(y > 0);\n\
+                 y\n\
+             }",
+            &VerificationOptions::default(),
+        )
+        .unwrap();
+        let sources: Vec<String> = report
+            .paths
+            .iter()
+            .map(|path| path.source.as_ref().unwrap().to_string())
+            .collect();
+        assert_eq!(sources, ["fn f, line 1", "fn g, lines 3-6"]);
+    }
+
+    #[test]
+    fn loop_obligations_are_separate_paths() {
+        let source = "fn sum(n: i32) -> i32 { pre!(n >= 0); let mut i = 0; let mut s = 0; \
+            while i < n { invariant!(0 <= i && i <= n && 2 * s == i * (i - 1)); \
+            s = s + i; i = i + 1; } post!(2 * s == n * (n - 1)); s }";
+        let report = run_verification_str(source, &VerificationOptions::default()).unwrap();
+        let kinds: Vec<_> = report.paths.iter().map(|path| path.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                Some(PathKind::PreToInvariant),
+                Some(PathKind::InvariantToPost),
+                Some(PathKind::InvariantToInvariant),
+            ]
+        );
+        assert!(report
+            .paths
+            .iter()
+            .all(|path| matches!(path.outcome, VerifyOutcome::Valid)));
+        // An invariant that isn't preserved only fails the iteration path
+        let report = run_verification_str(
+            &source.replace("2 * s == i * (i - 1)", "2 * s == i * (i - 1) && s <= i"),
+            &VerificationOptions::default(),
+        )
+        .unwrap();
+        let invalid: Vec<_> = report
+            .paths
+            .iter()
+            .map(|path| path.outcome.is_invalid())
+            .collect();
+        assert_eq!(invalid, [false, false, true]);
+    }
+
+    #[test]
+    fn broken_files_are_reported_and_the_others_verified() {
+        let dir = std::env::temp_dir().join(format!("secrust-broken-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("broken.rs"),
+            "fn f(x: i32) -> i32 {\n    pre!(x > 0);\n    let y = x +;\n    y\n}\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("fine.rs"),
+            "fn g(x: i32) -> i32 { pre!(x > 0); post!(x > 0); x }",
+        )
+        .unwrap();
+        std::fs::write(dir.join("latin1.rs"), b"// caf\xe9\n").unwrap();
+        let report = run_verification_dir(&dir, &VerificationOptions::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let broken = &report.files[0];
+        assert_eq!(
+            broken.error.as_deref(),
+            Some(
+                format!(
+                    "Syntax error in {} at line 3, column 16: expected expression",
+                    dir.join("broken.rs").display()
+                )
+                .as_str()
+            )
+        );
+        assert!(matches!(
+            report.files[1].report.paths[0].outcome,
+            VerifyOutcome::Valid
+        ));
+        assert_eq!(
+            report.files[2].error.as_deref(),
+            Some(
+                format!(
+                    "Cannot read {}: stream did not contain valid UTF-8",
+                    dir.join("latin1.rs").display()
+                )
+                .as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn target_and_hidden_directories_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("secrust-skipped-{}", std::process::id()));
+        let source = "fn g(x: i32) -> i32 { pre!(x > 0); post!(x > 0); x }";
+        for subdir in ["src", "target/debug/build", ".git"] {
+            std::fs::create_dir_all(dir.join(subdir)).unwrap();
+            std::fs::write(dir.join(subdir).join("lib.rs"), source).unwrap();
+        }
+        let report = run_verification_dir(&dir, &VerificationOptions::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let files: Vec<_> = report.files.iter().map(|file| file.file.clone()).collect();
+        assert_eq!(files, [dir.join("src").join("lib.rs")]);
+    }
+
+    #[test]
+    fn a_function_missing_from_every_file_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("secrust-function-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("a.rs"),
+            "fn f(x: i32) -> i32 { pre!(x > 0); post!(x > 0); x }",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("b.rs"),
+            "fn g(x: i32) -> i32 { pre!(x > 0); post!(x > 0); x }",
+        )
+        .unwrap();
+        let options = |function: &str| VerificationOptions {
+            function: Some(function.to_string()),
+            ..VerificationOptions::default()
+        };
+        let missing = run_verification_dir(&dir, &options("nosuch")).map(|_| ());
+        let found = run_verification_dir(&dir, &options("g")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "Function 'nosuch' not found among the annotated functions: f, g"
+        );
+        assert_eq!(found.files.len(), 1);
+        assert_eq!(found.files[0].file, dir.join("b.rs"));
+    }
 }
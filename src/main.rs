@@ -1,20 +1,48 @@
-use std::path::PathBuf;
-use std::process::exit;
 use clap::{Arg, Command};
-use secrust::run_verification;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use secrust::{
+    default_cache_path, dump_cfg, emit_verification_conditions, run_verification,
+    run_verification_dir, IntWidth, PathKind, PathResult, PathSource, VerificationOptions,
+    VerificationReport, VerifyOutcome, CACHE_FILE_NAME, DEFAULT_OUT_DIR, DEFAULT_TIMEOUT_MS,
+};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+// Prints the library's log messages to stderr, warnings and errors with their level.
+// Messages of dependencies (like the z3 crate's) are left out.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with("secrust")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => eprintln!("Error: {}", record.args()),
+            Level::Warn => eprintln!("Warning: {}", record.args()),
+            _ => eprintln!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
 
 fn main() {
-    // print args
     let raw_args: Vec<String> = std::env::args().collect();
-    println!("Raw arguments: {:?}", raw_args);
 
     let adjusted_args: Vec<String> = raw_args
         .iter()
-        .skip_while(|arg| !arg.contains("secrust-verify")) 
-        .skip(1) 
+        .skip_while(|arg| !arg.contains("secrust-verify"))
+        .skip(1)
         .map(|arg| arg.clone())
         .collect();
-    
+
     // parsing args using clap
     let matches = Command::new("Secrust Verification Tool")
         .version("1.0")
@@ -22,7 +50,7 @@ fn main() {
         .about("Verifies Rust code using Secrust analysis and optionally generates a DOT graph")
         .arg(
             Arg::new("file")
-                .help("The input file to verify")
+                .help("The input file to verify, or a directory to verify all its .rs files")
                 .required(true)
                 .index(1),  // positional file arg
         )
@@ -32,6 +60,139 @@ fn main() {
                 .help("Generate a DOT graph representation of the CFG")
                 .action(clap::ArgAction::SetTrue),  // check the flag is here
         )
+        .arg(
+            Arg::new("dot-wp")
+                .long("dot-wp")
+                .help("Like --dot, also labeling each node of the basic path DOT files with the weakest precondition at that node")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("out-dir")
+                .long("out-dir")
+                .value_name("DIR")
+                .help(format!("Directory the DOT files of --dot and --dot-wp are written to (default: {})", DEFAULT_OUT_DIR)),
+        )
+        .arg(
+            Arg::new("no-cache")
+                .long("no-cache")
+                .help(format!("Verify every function again instead of reusing the results of unchanged ones from target/{} of the crate", CACHE_FILE_NAME))
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("proof")
+                .long("proof")
+                .help("Keep Z3's proof of each valid path in the report, for audits (slower, and proofs can be large)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("conditions")
+                .long("conditions")
+                .value_name("PATH")
+                .help("JSON file with external method conditions (default: src/config/conditions.json)"),
+        )
+        .arg(
+            Arg::new("timeout-ms")
+                .long("timeout-ms")
+                .value_name("MS")
+                .help(format!(
+                    "Solver timeout per path in milliseconds (default: {})",
+                    DEFAULT_TIMEOUT_MS
+                ))
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("function")
+                .long("function")
+                .value_name("NAME")
+                .help("Only verify the annotated function with this name"),
+        )
+        .arg(
+            Arg::new("emit-smt2")
+                .long("emit-smt2")
+                .value_name("DIR")
+                .help("Write the condition of every path as a standalone SMT-LIB2 file (path_N.smt2) to DIR"),
+        )
+        .arg(
+            Arg::new("emit-vc")
+                .long("emit-vc")
+                .help("Print the verification condition of every path without solving it")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["dot", "dot-wp", "emit-smt2"]),
+        )
+        .arg(
+            Arg::new("dump-cfg")
+                .long("dump-cfg")
+                .help("Print the nodes and edges of the CFG as text, without generating paths or solving")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with_all(["dot", "dot-wp", "emit-smt2", "emit-vc", "format"]),
+        )
+        .arg(
+            Arg::new("de-morgan")
+                .long("de-morgan")
+                .help("Negate the conditions of false branches with De Morgan's laws, e.g. 'i >= n || sum < 0' instead of '!(i < n && sum >= 0)'")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("int-width")
+                .long("int-width")
+                .value_name("BITS")
+                .help("Model integers as BITS-bit values with wrapping arithmetic instead of unbounded ones")
+                .value_parser(clap::value_parser!(u32).range(1..=64)),
+        )
+        .arg(
+            Arg::new("unsigned")
+                .long("unsigned")
+                .help("With --int-width, compare, divide and shift integers as unsigned")
+                .action(clap::ArgAction::SetTrue)
+                .requires("int-width"),
+        )
+        .arg(
+            Arg::new("assert")
+                .long("assert")
+                .value_name("CONDITION")
+                .help("Check CONDITION as an extra postcondition of every verified function, e.g. 'result > 0' (repeatable)")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("z3-param")
+                .long("z3-param")
+                .value_name("KEY=VALUE")
+                .help("Set a Z3 global parameter, e.g. smt.random_seed=42 for reproducible counterexamples (repeatable)")
+                .action(clap::ArgAction::Append)
+                .value_parser(parse_z3_param),
+        )
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .short('j')
+                .value_name("N")
+                .help("Number of paths verified in parallel (default: one per CPU core, 1 with --verbose)")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .short('v')
+                .help("Print implications, solver output and counterexamples for every path")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .help("Only print the results: no header, warnings or progress messages")
+                .action(clap::ArgAction::SetTrue)
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Output format of the results: text or json (a single JSON report on stdout)")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .conflicts_with("verbose"),
+        )
         .try_get_matches_from(&adjusted_args)
         .unwrap_or_else(|err| {
             eprintln!("{}", err);
@@ -44,15 +205,317 @@ fn main() {
 
     // check if the dot flag was provided
     let generate_dot = *matches.get_one::<bool>("dot").unwrap_or(&false);
+    let dot_wp = *matches.get_one::<bool>("dot-wp").unwrap_or(&false);
+    let verbose = *matches.get_one::<bool>("verbose").unwrap_or(&false);
+    let quiet = *matches.get_one::<bool>("quiet").unwrap_or(&false);
+    let conditions_path = matches.get_one::<String>("conditions").map(PathBuf::from);
+    let function = matches.get_one::<String>("function").cloned();
+    let smt2_dir = matches.get_one::<String>("emit-smt2").map(PathBuf::from);
+    // results of unchanged functions are reused unless disabled
+    let cache_path = (!*matches.get_one::<bool>("no-cache").unwrap_or(&false))
+        .then(|| default_cache_path(&file_path));
+    let proof = *matches.get_one::<bool>("proof").unwrap_or(&false);
+    let out_dir = PathBuf::from(
+        matches
+            .get_one::<String>("out-dir")
+            .map_or(DEFAULT_OUT_DIR, String::as_str),
+    );
+    let emit_vc = *matches.get_one::<bool>("emit-vc").unwrap_or(&false);
+    let print_cfg = *matches.get_one::<bool>("dump-cfg").unwrap_or(&false);
+    let de_morgan = *matches.get_one::<bool>("de-morgan").unwrap_or(&false);
+    let int_width = matches.get_one::<u32>("int-width").map(|&bits| IntWidth {
+        bits,
+        signed: !*matches.get_one::<bool>("unsigned").unwrap_or(&false),
+    });
+    let z3_params: Vec<(String, String)> = matches
+        .get_many::<(String, String)>("z3-param")
+        .map(|params| params.cloned().collect())
+        .unwrap_or_default();
+    let extra_postconditions: Vec<String> = matches
+        .get_many::<String>("assert")
+        .map(|conditions| conditions.cloned().collect())
+        .unwrap_or_default();
+    let timeout_ms = *matches
+        .get_one::<u32>("timeout-ms")
+        .unwrap_or(&DEFAULT_TIMEOUT_MS);
+    // verbose output of paths verified in parallel would interleave, so default to one job
+    let jobs = *matches.get_one::<usize>("jobs").unwrap_or(&0);
 
-    println!("Running Secrust verification on file: {:?}", file_path);
-    println!("Generate DOT graph: {}", generate_dot);
+    let json = matches.get_one::<String>("format").map(String::as_str) == Some("json");
 
-    // run verification function with the provided file and generate_dot flag
-    if let Err(e) = run_verification(&file_path, generate_dot) {
-        eprintln!("Verification failed: {}", e);
-        exit(1);
+    // errors only with --quiet, debug messages with --verbose
+    let log_level = if quiet {
+        LevelFilter::Error
+    } else if verbose {
+        LevelFilter::Debug
     } else {
+        LevelFilter::Info
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log_level);
+    }
+
+    // keep stdout to the JSON report alone
+    if !json && !quiet {
+        // print args
+        println!("Raw arguments: {:?}", raw_args);
+        println!("Running Secrust verification on file: {:?}", file_path);
+        println!("Generate DOT graph: {}", generate_dot);
+    }
+
+    let options = VerificationOptions {
+        generate_dot,
+        dot_wp,
+        verbose,
+        conditions_path,
+        timeout_ms,
+        jobs,
+        smt2_dir,
+        function,
+        int_width,
+        external_methods: Vec::new(),
+        de_morgan,
+        z3_params,
+        extra_postconditions,
+        out_dir,
+        cache_path,
+        proof,
+    };
+
+    if file_path.is_dir() {
+        // the CFG and conditions are printed for one file at a time
+        if print_cfg || emit_vc {
+            eprintln!("--dump-cfg and --emit-vc take a single file, not a directory");
+            exit(1);
+        }
+        verify_dir(&file_path, &options, json, quiet);
+        return;
+    }
+
+    // only print the CFG, no paths are generated
+    if print_cfg {
+        match dump_cfg(&file_path, &options) {
+            Err(e) => {
+                eprintln!("Verification failed: {}", e);
+                exit(1);
+            }
+            Ok(dump) => print!("{}", dump),
+        }
+        return;
+    }
+
+    // only print the conditions, Z3 isn't called
+    if emit_vc {
+        match emit_verification_conditions(&file_path, &options) {
+            Err(e) => {
+                eprintln!("Verification failed: {}", e);
+                exit(1);
+            }
+            Ok(conditions) => {
+                if json {
+                    match serde_json::to_string_pretty(&conditions) {
+                        Ok(conditions_json) => println!("{}", conditions_json),
+                        Err(e) => {
+                            eprintln!("Failed to serialize the conditions: {}", e);
+                            exit(1);
+                        }
+                    }
+                } else {
+                    for (i, condition) in conditions.iter().enumerate() {
+                        println!(
+                            "{}: {}",
+                            path_name(i, condition.kind, condition.source.as_ref()),
+                            condition.implication
+                        );
+                    }
+                }
+            }
+        }
+        return;
+    }
+
+    // run verification function with the provided file and options
+    match run_verification(&file_path, &options) {
+        Err(e) => {
+            eprintln!("Verification failed: {}", e);
+            exit(1);
+        }
+        Ok(report) => {
+            if json {
+                match serde_json::to_string_pretty(&report) {
+                    Ok(report_json) => println!("{}", report_json),
+                    Err(e) => {
+                        eprintln!("Failed to serialize the report: {}", e);
+                        exit(1);
+                    }
+                }
+            } else {
+                print_report(&report);
+            }
+            if report.has_invalid() {
+                eprintln!("Verification failed: at least one path is invalid.");
+                exit(1);
+            }
+            if report.has_errors() {
+                eprintln!("Verification incomplete: at least one path could not be checked.");
+                exit(1);
+            }
+            // An undecided path isn't proven, so it mustn't pass as a success
+            if report.has_undecided() {
+                eprintln!(
+                    "Verification incomplete: the solver could not decide at least one path."
+                );
+                exit(1);
+            }
+            if !json && !quiet {
+                println!("Verification completed successfully.");
+            }
+        }
+    }
+}
+
+// "Path 1 [pre -> post] (fn f, lines 2-5)" for the path at index 0, numbered across the
+// functions of the file, so the function tells which one it checks
+fn path_name(i: usize, kind: Option<PathKind>, source: Option<&PathSource>) -> String {
+    let mut name = format!("Path {}", i + 1);
+    if let Some(kind) = kind {
+        name.push_str(&format!(" [{}]", kind));
+    }
+    if let Some(source) = source {
+        name.push_str(&format!(" ({})", source));
+    }
+    name
+}
+
+// Results of every path of a file, with a summary
+fn print_report(report: &VerificationReport) {
+    for (i, path) in report.paths.iter().enumerate() {
+        let name = path_name(i, path.kind, path.source.as_ref());
+        match (&path.outcome, &path.target) {
+            (VerifyOutcome::Invalid(_), Some(target)) => {
+                println!("{}: {} ({} may not hold)", name, path.outcome, target)
+            }
+            _ => println!("{}: {}", name, path.outcome),
+        }
+        if let Some(proof) = &path.proof {
+            println!("Proof:\n{}", proof);
+        }
+    }
+    // Paths from a precondition that can't hold prove nothing, even when valid
+    for unsatisfiable in &report.unsatisfiable_preconditions {
+        println!("Warning: {}", unsatisfiable);
+    }
+    if let Some(dot_dir) = &report.dot_dir {
+        println!("DOT files saved in: {}", dot_dir.display());
+    }
+    print_obligations(&report.paths);
+    println!("Summary: {}", report.summary());
+}
+
+// Table of the obligations with the time each took, to spot expensive annotations
+fn print_obligations(paths: &[PathResult]) {
+    if paths.is_empty() {
+        return;
+    }
+    let header = ["Path", "Function", "Kind", "Line", "Outcome", "Time (ms)"].map(String::from);
+    let rows: Vec<[String; 6]> = paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            [
+                (i + 1).to_string(),
+                path.source
+                    .as_ref()
+                    .map_or("-".to_string(), |source| source.function.clone()),
+                path.kind.map_or("-".to_string(), |kind| kind.to_string()),
+                path.source
+                    .as_ref()
+                    .map_or("-".to_string(), |source| source.start_line.to_string()),
+                // the reason of an error is printed with the path already
+                match path.outcome {
+                    VerifyOutcome::Error(_) => "error".to_string(),
+                    ref outcome => outcome.to_string(),
+                },
+                path.time_ms
+                    .map_or("cached".to_string(), |time_ms| format!("{:.1}", time_ms)),
+            ]
+        })
+        .collect();
+    let mut widths = header.clone().map(|cell| cell.len());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    println!();
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .enumerate()
+            .map(|(column, (cell, width))| {
+                // numbers are right-aligned
+                if [0, 3, 5].contains(&column) {
+                    format!("{:>width$}", cell, width = width)
+                } else {
+                    format!("{:<width$}", cell, width = width)
+                }
+            })
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    println!();
+}
+
+// Verifies the .rs files of a directory, printing each file's results and a total,
+// and exits like for a single file
+fn verify_dir(dir: &Path, options: &VerificationOptions, json: bool, quiet: bool) {
+    let report = match run_verification_dir(dir, options) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Verification failed: {}", e);
+            exit(1);
+        }
+    };
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(report_json) => println!("{}", report_json),
+            Err(e) => {
+                eprintln!("Failed to serialize the report: {}", e);
+                exit(1);
+            }
+        }
+    } else {
+        for file in &report.files {
+            println!("== {}", file.file.display());
+            match &file.error {
+                Some(error) => println!("error: {}", error),
+                None => print_report(&file.report),
+            }
+        }
+        println!("Total ({} files): {}", report.files.len(), report.summary());
+    }
+    if report.has_invalid() {
+        eprintln!("Verification failed: at least one path is invalid.");
+        exit(1);
+    }
+    if report.has_errors() {
+        eprintln!("Verification incomplete: at least one file or path could not be checked.");
+        exit(1);
+    }
+    if report.has_undecided() {
+        eprintln!("Verification incomplete: the solver could not decide at least one path.");
+        exit(1);
+    }
+    if !json && !quiet {
         println!("Verification completed successfully.");
     }
 }
+
+// "key=value" of --z3-param
+fn parse_z3_param(param: &str) -> Result<(String, String), String> {
+    match param.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", param)),
+    }
+}
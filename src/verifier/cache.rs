@@ -0,0 +1,153 @@
+use crate::verifier::{Counterexample, IntWidth, VerificationCondition, VerifyOutcome};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+// Name of the file the command line keeps the cache in, see 'default_cache_path'
+pub const CACHE_FILE_NAME: &str = "secrust-cache";
+
+// Cache of the command line for a verified file or directory: in the 'target' directory of the
+// crate it belongs to, like Cargo's own build outputs, or next to it outside of a crate
+pub fn default_cache_path(input: &Path) -> PathBuf {
+    let input = std::fs::canonicalize(input).unwrap_or(input.to_path_buf());
+    let dir = if input.is_dir() {
+        input.as_path()
+    } else {
+        input.parent().unwrap_or(Path::new("."))
+    };
+    match dir.ancestors().find(|dir| dir.join("Cargo.toml").is_file()) {
+        Some(crate_dir) => crate_dir.join("target").join(CACHE_FILE_NAME),
+        None => dir.join(format!(".{}", CACHE_FILE_NAME)),
+    }
+}
+
+// Outcomes of the paths of each function from earlier runs, by file and function name.
+// An entry is reused while the hash of the function's verification conditions is unchanged.
+// The conditions are derived from the function's body and from the contracts, constants and
+// external conditions it uses, so changing any of them invalidates it, but editing another
+// function of the file doesn't.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VerificationCache {
+    files: HashMap<String, HashMap<String, CachedFunction>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedFunction {
+    hash: String,
+    outcomes: Vec<CachedOutcome>, // in path order
+}
+
+// Only outcomes that don't depend on the solver's time budget are kept
+#[derive(Debug, Serialize, Deserialize)]
+enum CachedOutcome {
+    Valid,
+    Invalid(Vec<(String, String)>), // assignments of the counterexample
+}
+
+impl CachedOutcome {
+    fn of(outcome: &VerifyOutcome) -> Option<Self> {
+        match outcome {
+            VerifyOutcome::Valid => Some(CachedOutcome::Valid),
+            VerifyOutcome::Invalid(counterexample) => {
+                Some(CachedOutcome::Invalid(counterexample.assignments.clone()))
+            }
+            VerifyOutcome::Unknown | VerifyOutcome::Timeout | VerifyOutcome::Error(_) => None,
+        }
+    }
+
+    fn to_outcome(&self) -> VerifyOutcome {
+        match self {
+            CachedOutcome::Valid => VerifyOutcome::Valid,
+            CachedOutcome::Invalid(assignments) => VerifyOutcome::Invalid(Counterexample {
+                assignments: assignments.clone(),
+            }),
+        }
+    }
+}
+
+impl VerificationCache {
+    // A missing cache is empty, and an unreadable one is ignored and rebuilt
+    pub fn load(path: &Path) -> Self {
+        let Ok(content) = std::fs::read_to_string(path) else {
+            return Self::default();
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            log::warn!("Ignoring the cache {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Outcomes of the paths of 'function', if they were stored with the same hash
+    pub fn lookup(&self, file: &str, function: &str, hash: &str) -> Option<Vec<VerifyOutcome>> {
+        let cached = self.files.get(file)?.get(function)?;
+        (cached.hash == hash).then(|| {
+            cached
+                .outcomes
+                .iter()
+                .map(CachedOutcome::to_outcome)
+                .collect()
+        })
+    }
+
+    // Replaces the entry of 'function'. A function with an outcome that isn't kept, like a
+    // timeout, loses its entry and is verified again next time.
+    pub fn store(&mut self, file: &str, function: &str, hash: String, outcomes: &[VerifyOutcome]) {
+        let functions = self.files.entry(file.to_string()).or_default();
+        match outcomes.iter().map(CachedOutcome::of).collect() {
+            Some(outcomes) => {
+                functions.insert(function.to_string(), CachedFunction { hash, outcomes });
+            }
+            None => {
+                functions.remove(function);
+            }
+        }
+    }
+}
+
+// Hash of the conditions of a function and of the options their outcomes depend on
+pub fn conditions_hash(
+    conditions: &[&VerificationCondition],
+    int_width: Option<IntWidth>,
+    z3_params: &[(String, String)],
+) -> String {
+    // Only stable for one build, which is all a cache needs
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    format!("{:?}", int_width).hash(&mut hasher);
+    z3_params.hash(&mut hasher);
+    for condition in conditions {
+        condition.implication.hash(&mut hasher);
+        condition.bool_vars.hash(&mut hasher);
+        condition.real_vars.hash(&mut hasher);
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_cache_path, CACHE_FILE_NAME};
+    use std::path::Path;
+
+    #[test]
+    fn the_cache_is_kept_in_the_target_directory_of_the_crate() {
+        let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .canonicalize()
+            .unwrap();
+        let cache_path = crate_dir.join("target").join(CACHE_FILE_NAME);
+        assert_eq!(
+            default_cache_path(&crate_dir.join("src/lib.rs")),
+            cache_path
+        );
+        assert_eq!(default_cache_path(&crate_dir.join("src")), cache_path);
+    }
+}
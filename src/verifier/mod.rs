@@ -1,5 +1,9 @@
+mod cache;
+mod report;
 mod z3_parser;
 mod z3_verifier;
 
+pub use cache::*;
+pub use report::*;
 pub use z3_parser::*;
 pub use z3_verifier::*;
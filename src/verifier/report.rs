@@ -0,0 +1,302 @@
+use crate::cfg_builder::node::SourceLocation;
+use crate::verifier::VerifyOutcome;
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::ops::AddAssign;
+use std::path::PathBuf;
+
+// Annotation a basic path ends in, i.e. the assertion the path has to establish
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathTarget {
+    pub kind: String, // "Postcondition", "Invariant", ...
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for PathTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} at {}", self.kind, location),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+// Function a basic path belongs to and the source lines its nodes span
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathSource {
+    pub function: String,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+impl fmt::Display for PathSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start_line == self.end_line {
+            write!(f, "fn {}, line {}", self.function, self.start_line)
+        } else {
+            write!(
+                f,
+                "fn {}, lines {}-{}",
+                self.function, self.start_line, self.end_line
+            )
+        }
+    }
+}
+
+// Proof obligation of a basic path, by the annotations it starts and ends at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    PreToPost,                         // the function body, for paths without loops
+    PreToInvariant,                    // a loop invariant holds when the loop is entered
+    InvariantToInvariant, // a loop iteration preserves the invariant, or establishes the next one
+    InvariantToPost,      // the postcondition follows from the invariant once the loop exits
+    Other(&'static str, &'static str), // from and to assertions, call preconditions, cutoffs...
+}
+
+impl PathKind {
+    // 'kinds' as reported by 'CfgNode::annotation_kind'
+    pub fn from_annotation_kinds(start: &'static str, end: &'static str) -> Self {
+        match (start, end) {
+            ("Precondition", "Postcondition") => PathKind::PreToPost,
+            ("Precondition", "Invariant") => PathKind::PreToInvariant,
+            ("Invariant", "Invariant") => PathKind::InvariantToInvariant,
+            ("Invariant", "Postcondition") => PathKind::InvariantToPost,
+            (start, end) => PathKind::Other(start, end),
+        }
+    }
+}
+
+impl fmt::Display for PathKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathKind::PreToPost => write!(f, "pre -> post"),
+            PathKind::PreToInvariant => write!(f, "pre -> invariant"),
+            PathKind::InvariantToInvariant => write!(f, "invariant -> invariant"),
+            PathKind::InvariantToPost => write!(f, "invariant -> post"),
+            PathKind::Other(start, end) => {
+                write!(f, "{} -> {}", short_name(start), short_name(end))
+            }
+        }
+    }
+}
+
+// "Precondition" -> "pre", "Call precondition" -> "call precondition"
+fn short_name(annotation_kind: &str) -> String {
+    match annotation_kind {
+        "Precondition" => "pre".to_string(),
+        "Postcondition" => "post".to_string(),
+        kind => kind.to_lowercase(),
+    }
+}
+
+// Reported like in the text output, e.g. "pre -> invariant"
+impl Serialize for PathKind {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Implication of a basic path, before it is handed to Z3
+#[derive(Debug, Clone, Serialize)]
+pub struct VerificationCondition {
+    pub implication: String,
+    pub target: Option<PathTarget>,
+    pub kind: Option<PathKind>,
+    pub source: Option<PathSource>,
+    // variables of the implication holding booleans, which it may not use as booleans itself
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bool_vars: Vec<String>,
+    // variables of the implication declared as floats
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub real_vars: Vec<String>,
+}
+
+// Outcome of verifying one basic path
+#[derive(Debug, Clone, Serialize)]
+pub struct PathResult {
+    pub implication: String,
+    #[serde(flatten)]
+    pub outcome: VerifyOutcome,
+    pub target: Option<PathTarget>,
+    pub kind: Option<PathKind>,
+    pub source: Option<PathSource>,
+    // Z3's proof of a valid path, when proofs were requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+    pub time_ms: Option<f64>, // time spent translating and solving the path, None if cached
+}
+
+// Number of paths per outcome
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct VerificationSummary {
+    pub valid: usize,
+    pub invalid: usize,
+    pub unknown: usize,
+    pub timeout: usize,
+    pub error: usize,
+}
+
+impl AddAssign for VerificationSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.valid += other.valid;
+        self.invalid += other.invalid;
+        self.unknown += other.unknown;
+        self.timeout += other.timeout;
+        self.error += other.error;
+    }
+}
+
+impl fmt::Display for VerificationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} valid, {} invalid, {} unknown, {} timeout, {} error",
+            self.valid, self.invalid, self.unknown, self.timeout, self.error
+        )
+    }
+}
+
+// Preconditions of a function that no values satisfy, so every path starting at them
+// verifies vacuously
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnsatisfiablePrecondition {
+    pub function: String,
+    pub precondition: String, // conjunction of the function's preconditions
+    pub location: Option<SourceLocation>, // of the first precondition
+}
+
+impl fmt::Display for UnsatisfiablePrecondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the preconditions of fn {} can never hold ({})",
+            self.function, self.precondition
+        )?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        write!(f, ", so its paths verify vacuously")
+    }
+}
+
+// Per-path results of a verification run, in basic path order
+#[derive(Debug, Clone, Default)]
+pub struct VerificationReport {
+    pub paths: Vec<PathResult>,
+    pub unsatisfiable_preconditions: Vec<UnsatisfiablePrecondition>,
+    pub dot_dir: Option<PathBuf>, // where the DOT files were written, if they were
+}
+
+// Serialized with its summary, so consumers don't have to count outcomes themselves.
+// Unsatisfiable preconditions are only listed when there are some, and the DOT directory
+// when DOT files were written.
+impl Serialize for VerificationReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut report = serializer.serialize_struct("VerificationReport", 4)?;
+        report.serialize_field("paths", &self.paths)?;
+        if self.unsatisfiable_preconditions.is_empty() {
+            report.skip_field("unsatisfiable_preconditions")?;
+        } else {
+            report.serialize_field(
+                "unsatisfiable_preconditions",
+                &self.unsatisfiable_preconditions,
+            )?;
+        }
+        match &self.dot_dir {
+            Some(dot_dir) => report.serialize_field("dot_dir", dot_dir)?,
+            None => report.skip_field("dot_dir")?,
+        }
+        report.serialize_field("summary", &self.summary())?;
+        report.end()
+    }
+}
+
+impl VerificationReport {
+    pub fn summary(&self) -> VerificationSummary {
+        let mut summary = VerificationSummary::default();
+        for path in &self.paths {
+            let count = match path.outcome {
+                VerifyOutcome::Valid => &mut summary.valid,
+                VerifyOutcome::Invalid(_) => &mut summary.invalid,
+                VerifyOutcome::Unknown => &mut summary.unknown,
+                VerifyOutcome::Timeout => &mut summary.timeout,
+                VerifyOutcome::Error(_) => &mut summary.error,
+            };
+            *count += 1;
+        }
+        summary
+    }
+
+    pub fn has_invalid(&self) -> bool {
+        self.paths.iter().any(|path| path.outcome.is_invalid())
+    }
+
+    // Paths whose condition couldn't be translated for Z3
+    pub fn has_errors(&self) -> bool {
+        self.paths
+            .iter()
+            .any(|path| matches!(path.outcome, VerifyOutcome::Error(_)))
+    }
+
+    // Paths Z3 couldn't decide, within the timeout or at all: they are neither proven nor refuted
+    pub fn has_undecided(&self) -> bool {
+        self.paths.iter().any(|path| {
+            matches!(
+                path.outcome,
+                VerifyOutcome::Unknown | VerifyOutcome::Timeout
+            )
+        })
+    }
+}
+
+// Results of one file of a directory
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub file: PathBuf,
+    #[serde(flatten)]
+    pub report: VerificationReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // why the file couldn't be verified, e.g. a syntax error
+}
+
+// Results of the files of a directory with annotated functions, in path order
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryReport {
+    pub files: Vec<FileReport>,
+}
+
+// Serialized with the summary of all files
+impl Serialize for DirectoryReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut report = serializer.serialize_struct("DirectoryReport", 2)?;
+        report.serialize_field("files", &self.files)?;
+        report.serialize_field("summary", &self.summary())?;
+        report.end()
+    }
+}
+
+impl DirectoryReport {
+    pub fn summary(&self) -> VerificationSummary {
+        let mut summary = VerificationSummary::default();
+        for file in &self.files {
+            summary += file.report.summary();
+        }
+        summary
+    }
+
+    pub fn has_invalid(&self) -> bool {
+        self.files.iter().any(|file| file.report.has_invalid())
+    }
+
+    // Files that couldn't be verified count like paths that couldn't be checked
+    pub fn has_errors(&self) -> bool {
+        self.files
+            .iter()
+            .any(|file| file.error.is_some() || file.report.has_errors())
+    }
+
+    pub fn has_undecided(&self) -> bool {
+        self.files.iter().any(|file| file.report.has_undecided())
+    }
+}
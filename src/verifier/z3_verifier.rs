@@ -1,87 +1,491 @@
-use crate::verifier::z3_parser;
+use crate::verifier::z3_parser::{self, IntWidth, ParseError};
 use crate::Z3Var;
+use serde::{Serialize, Serializer};
 use std::collections::HashMap;
-use z3::{ast, Config, Context, SatResult, Solver};
+use std::fmt;
+use z3::{ast, Config, Context, Model, Params, SatResult, Solver};
 
-// Verify Z3 condition and print the model if satisfiable
+// Default time the solver may spend on a single path before giving up
+pub const DEFAULT_TIMEOUT_MS: u32 = 10_000;
+
+// Variable assignments under which a verification condition fails, sorted by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counterexample {
+    pub assignments: Vec<(String, String)>,
+}
+
+// Serialized as a map from variable name to value
+impl Serialize for Counterexample {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.assignments.iter().map(|(name, value)| (name, value)))
+    }
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.assignments {
+            writeln!(f, "{} = {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+// Result of checking a single verification condition
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "details", rename_all = "lowercase")]
+pub enum VerifyOutcome {
+    Valid,
+    Invalid(Counterexample),
+    Unknown,
+    Timeout,
+    Error(ParseError), // the path condition couldn't be translated for Z3
+}
+
+impl VerifyOutcome {
+    pub fn is_invalid(&self) -> bool {
+        matches!(self, VerifyOutcome::Invalid(_))
+    }
+}
+
+impl fmt::Display for VerifyOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyOutcome::Valid => write!(f, "valid"),
+            VerifyOutcome::Invalid(_) => write!(f, "invalid"),
+            VerifyOutcome::Unknown => write!(f, "unknown"),
+            VerifyOutcome::Timeout => write!(f, "timeout"),
+            VerifyOutcome::Error(e) => write!(f, "error ({})", e),
+        }
+    }
+}
+
+// Verify Z3 condition, returning the model as a counterexample if the negation is satisfiable
 pub fn verify_condition(
     solver: &mut Solver,
     condition: &ast::Bool,
     vars: &HashMap<String, Z3Var>,
-) -> bool {
+    int_width: Option<IntWidth>,
+    verbose: bool,
+) -> VerifyOutcome {
+    check_condition(solver, condition, vars, int_width, verbose, false).0
+}
+
+// Like 'verify_condition', also returning Z3's proof that the negation is unsatisfiable when
+// the condition is valid. The solver's context must have been created by 'proof_config'.
+pub fn verify_condition_with_proof(
+    solver: &mut Solver,
+    condition: &ast::Bool,
+    vars: &HashMap<String, Z3Var>,
+    int_width: Option<IntWidth>,
+    verbose: bool,
+) -> (VerifyOutcome, Option<String>) {
+    check_condition(solver, condition, vars, int_width, verbose, true)
+}
+
+fn check_condition(
+    solver: &mut Solver,
+    condition: &ast::Bool,
+    vars: &HashMap<String, Z3Var>,
+    int_width: Option<IntWidth>,
+    verbose: bool,
+    proof: bool,
+) -> (VerifyOutcome, Option<String>) {
     solver.push();
     solver.assert(&condition.not()); // assert the negation for proof by contradiction
+    let mut unsat_proof = None;
     let result = match solver.check() {
         SatResult::Unsat => {
-            println!("Condition is valid (unsatisfiable when negated).\n");
-            true
+            if verbose {
+                println!("Condition is valid (unsatisfiable when negated).\n");
+            }
+            // Z3 asks for the proof before the scope is popped
+            if proof {
+                unsat_proof = solver.get_proof().map(|proof| format!("{:?}", proof));
+            }
+            VerifyOutcome::Valid
         }
         SatResult::Sat => {
-            println!("Condition is not valid (counterexample found).\n");
-            if let Some(model) = solver.get_model() {
+            let counterexample = solver
+                .get_model()
+                .map(|model| extract_counterexample(&model, vars, int_width))
+                .unwrap_or_default();
+            if verbose {
+                println!("Condition is not valid (counterexample found).\n");
                 println!("Counterexample model assignments:");
-                for (name, var) in vars {
-                    let value = match var {
-                        Z3Var::Int(ref int_var) => {
-                            model.eval(int_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Bool(ref bool_var) => {
-                            model.eval(bool_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Real(ref real_var) => {
-                            model.eval(real_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::BV(ref bv_var) => {
-                            model.eval(bv_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Float(ref float_var) => {
-                            model.eval(float_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Array(ref array_var) => {
-                            model.eval(array_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::String(ref string_var) => {
-                            model.eval(string_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Set(ref set_var) => {
-                            model.eval(set_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Datatype(ref datatype_var) => {
-                            model.eval(datatype_var, false).map(|v| format!("{:?}", v))
-                        }
-                        Z3Var::Dynamic(ref dynamic_var) => {
-                            model.eval(dynamic_var, false).map(|v| format!("{:?}", v))
-                        }
-                    };
-
-                    if let Some(value) = value {
-                        println!("{} = {}", name, value);
-                    }
-                }
-
-                println!();
+                println!("{}", counterexample);
             }
-            false
+            VerifyOutcome::Invalid(counterexample)
         }
         SatResult::Unknown => {
-            println!("Solver could not determine validity.\n");
-            false
+            let reason = solver.get_reason_unknown().unwrap_or_default();
+            if reason.contains("timeout") || reason.contains("canceled") {
+                if verbose {
+                    println!("Solver timed out before determining validity.\n");
+                }
+                VerifyOutcome::Timeout
+            } else {
+                if verbose {
+                    println!("Solver could not determine validity ({}).\n", reason);
+                }
+                VerifyOutcome::Unknown
+            }
         }
     };
     solver.pop(1);
+    (result, unsat_proof)
+}
+
+fn extract_counterexample(
+    model: &Model,
+    vars: &HashMap<String, Z3Var>,
+    int_width: Option<IntWidth>,
+) -> Counterexample {
+    let signed = int_width.is_none_or(|width| width.signed);
+    let mut assignments: Vec<(String, String)> = vars
+        .iter()
+        .filter_map(|(name, var)| {
+            let value = match var {
+                Z3Var::Int(ref int_var) => model.eval(int_var, false).map(|v| format!("{:?}", v)),
+                Z3Var::Bool(ref bool_var) => {
+                    model.eval(bool_var, false).map(|v| format!("{:?}", v))
+                }
+                Z3Var::Real(ref real_var) => {
+                    model.eval(real_var, false).map(|v| format!("{:?}", v))
+                }
+                // Fixed-width integers are shown as decimal numbers, like Ints
+                Z3Var::BV(ref bv_var) => model
+                    .eval(&bv_var.to_int(signed), true)
+                    .map(|v| format!("{:?}", v)),
+                Z3Var::Float(ref float_var) => {
+                    model.eval(float_var, false).map(|v| format!("{:?}", v))
+                }
+                Z3Var::Array(ref array_var) => {
+                    model.eval(array_var, false).map(|v| format!("{:?}", v))
+                }
+                Z3Var::String(ref string_var) => {
+                    model.eval(string_var, false).map(|v| format!("{:?}", v))
+                }
+                Z3Var::Set(ref set_var) => model.eval(set_var, false).map(|v| format!("{:?}", v)),
+                Z3Var::Datatype(ref datatype_var) => {
+                    model.eval(datatype_var, false).map(|v| format!("{:?}", v))
+                }
+                Z3Var::Dynamic(ref dynamic_var) => {
+                    model.eval(dynamic_var, false).map(|v| format!("{:?}", v))
+                }
+                Z3Var::Function(..) => None,
+            };
+            value.map(|value| (name.clone(), value))
+        })
+        .collect();
+    assignments.sort();
+    Counterexample { assignments }
+}
+
+// SMT-LIB2 script asserting the negation of the condition, runnable standalone by any
+// SMT-LIB2 solver: 'unsat' means the condition is valid
+pub fn condition_to_smt2(ctx: &Context, condition: &ast::Bool) -> String {
+    let solver = Solver::new(ctx);
+    solver.assert(&condition.not());
+    format!("{}(check-sat)\n", solver)
+}
+
+// Parse an implication like 'verify_str_implication' and export it as SMT-LIB2
+pub fn str_implication_to_smt2(
+    expr_str: &str,
+    int_width: Option<IntWidth>,
+    bool_vars: &[String],
+    real_vars: &[String],
+) -> Result<String, ParseError> {
+    let cfg = Config::new();
+    let ctx = Context::new(&cfg);
+    let parsed_expr = parse_implication(expr_str)?;
+    let (z3_condition, _vars) = z3_parser::generate_condition_and_vars(
+        &ctx,
+        &parsed_expr,
+        int_width,
+        bool_vars,
+        real_vars,
+    )?;
+    Ok(condition_to_smt2(&ctx, &z3_condition))
+}
+
+fn parse_implication(expr_str: &str) -> Result<syn::Expr, ParseError> {
+    syn::parse_str::<syn::Expr>(expr_str).map_err(|e| ParseError::InvalidSyntax(e.to_string()))
+}
+
+// Sets Z3 global parameters, e.g. ("smt.random_seed", "42") for reproducible counterexamples
+// or ("smt.arith.solver", "2"). They apply to the contexts created while the returned
+// 'Z3Params' is alive, in the whole process, and their previous values come back when it's
+// dropped. An unknown parameter or a value Z3 doesn't take is an error, and none are set then.
+pub fn set_z3_params(params: &[(String, String)]) -> Result<Z3Params, String> {
+    let mut set = Z3Params {
+        previous: Vec::new(),
+    };
+    if params.is_empty() {
+        return Ok(set);
+    }
+    // Z3 prints the list of all legal parameters on stdout when asked about an unknown one,
+    // and a warning when it ignores a value
+    let warning = z3::get_global_param("warning").unwrap_or_else(|| "true".to_string());
+    z3::set_global_param("warning", "false");
+    let unknown: Vec<&str> = params
+        .iter()
+        .filter(|(key, _)| z3::get_global_param(key).is_none())
+        .map(|(key, _)| key.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        z3::set_global_param("warning", &warning);
+        return Err(format!("Unknown Z3 parameter: {}", unknown.join(", ")));
+    }
+
+    // Z3 keeps the previous value of a parameter given one it can't parse
+    let mut invalid = Vec::new();
+    for (key, value) in params {
+        let previous = z3::get_global_param(key).unwrap_or_default();
+        z3::set_global_param(key, value);
+        set.previous.push((key.clone(), previous));
+        if z3::get_global_param(key).as_deref() != Some(value.as_str()) {
+            invalid.push(format!("{}={}", key, value));
+        }
+    }
+    z3::set_global_param("warning", &warning);
+    if !invalid.is_empty() {
+        return Err(format!(
+            "Invalid Z3 parameter value: {}",
+            invalid.join(", ")
+        ));
+    }
+    Ok(set)
+}
+
+// Z3 global parameters set by 'set_z3_params', restored to their previous values when dropped
+pub struct Z3Params {
+    previous: Vec<(String, String)>, // in the order they were set
+}
+
+impl Drop for Z3Params {
+    fn drop(&mut self) {
+        for (key, value) in self.previous.iter().rev() {
+            z3::set_global_param(key, value);
+        }
+    }
+}
+
+// Configuration of a context whose solvers record proofs, for 'verify_condition_with_proof'.
+// Recording them makes solving slower, and a proof can be much larger than its condition.
+pub fn proof_config() -> Config {
+    let mut cfg = Config::new();
+    cfg.set_proof_generation(true);
+    cfg
+}
+
+// Solver for a run's shared Z3 context. Each path is checked in its own 'push'/'pop' scope,
+// so one solver serves all paths.
+pub fn new_solver(ctx: &Context, timeout_ms: u32) -> Solver<'_> {
+    let solver = Solver::new(ctx);
+
+    // Bound the time spent on each path so nonlinear conditions can't stall the run
+    let mut params = Params::new(ctx);
+    params.set_u32("timeout", timeout_ms);
+    solver.set_params(&params);
+    solver
+}
+
+// Verify an implication with an existing context and solver, see 'new_solver'.
+// 'bool_vars' are the variables of the implication known to hold booleans, and 'real_vars'
+// the ones declared as floats.
+pub fn verify_str_implication_with<'ctx>(
+    ctx: &'ctx Context,
+    solver: &mut Solver<'ctx>,
+    expr_str: &str,
+    int_width: Option<IntWidth>,
+    bool_vars: &[String],
+    real_vars: &[String],
+    verbose: bool,
+) -> Result<VerifyOutcome, ParseError> {
+    let (z3_condition, vars) =
+        translate_implication(ctx, expr_str, int_width, bool_vars, real_vars, verbose)?;
+    Ok(verify_condition(
+        solver,
+        &z3_condition,
+        &vars,
+        int_width,
+        verbose,
+    ))
+}
+
+// Like 'verify_str_implication_with', with the proof of a valid implication, see
+// 'verify_condition_with_proof'
+pub fn verify_str_implication_with_proof<'ctx>(
+    ctx: &'ctx Context,
+    solver: &mut Solver<'ctx>,
+    expr_str: &str,
+    int_width: Option<IntWidth>,
+    bool_vars: &[String],
+    real_vars: &[String],
+    verbose: bool,
+) -> Result<(VerifyOutcome, Option<String>), ParseError> {
+    let (z3_condition, vars) =
+        translate_implication(ctx, expr_str, int_width, bool_vars, real_vars, verbose)?;
+    Ok(verify_condition_with_proof(
+        solver,
+        &z3_condition,
+        &vars,
+        int_width,
+        verbose,
+    ))
+}
+
+fn translate_implication<'ctx>(
+    ctx: &'ctx Context,
+    expr_str: &str,
+    int_width: Option<IntWidth>,
+    bool_vars: &[String],
+    real_vars: &[String],
+    verbose: bool,
+) -> Result<(ast::Bool<'ctx>, HashMap<String, Z3Var<'ctx>>), ParseError> {
+    // Parse and process logical proposition
+    let parsed_expr = parse_implication(expr_str)?;
+    let (z3_condition, vars) =
+        z3_parser::generate_condition_and_vars(ctx, &parsed_expr, int_width, bool_vars, real_vars)?;
+    if verbose {
+        println!();
+        println!("Generated Z3 Condition:\n{}\n", z3_condition);
+    }
+    Ok((z3_condition, vars))
+}
+
+// Whether some values satisfy a condition itself, not its negation, e.g. to tell if
+// a precondition can hold at all
+pub fn check_satisfiable(solver: &mut Solver, condition: &ast::Bool) -> SatResult {
+    solver.push();
+    solver.assert(condition);
+    let result = solver.check();
+    solver.pop(1);
     result
 }
 
+// Like 'check_satisfiable' for a condition given as an expression, see 'new_solver'
+pub fn check_expr_satisfiable_with<'ctx>(
+    ctx: &'ctx Context,
+    solver: &mut Solver<'ctx>,
+    expr: &syn::Expr,
+    int_width: Option<IntWidth>,
+    bool_vars: &[String],
+    real_vars: &[String],
+) -> Result<SatResult, ParseError> {
+    let (z3_condition, _vars) =
+        z3_parser::generate_assumption_and_vars(ctx, expr, int_width, bool_vars, real_vars)?;
+    Ok(check_satisfiable(solver, &z3_condition))
+}
+
+// Check a single condition, e.g. '(x > 0) >> (x + 1 > 1)', with unbounded integers and the
+// default timeout, without printing anything. A condition that can't be translated is an 'Error'.
+pub fn verify_expr(expr: &syn::Expr) -> VerifyOutcome {
+    let cfg = Config::new();
+    let ctx = Context::new(&cfg);
+    let mut solver = new_solver(&ctx, DEFAULT_TIMEOUT_MS);
+    let outcome = match z3_parser::generate_condition_and_vars(&ctx, expr, None, &[], &[]) {
+        Ok((z3_condition, vars)) => {
+            verify_condition(&mut solver, &z3_condition, &vars, None, false)
+        }
+        Err(e) => VerifyOutcome::Error(e),
+    };
+    outcome
+}
+
+// Like 'verify_expr', parsing the condition from a string
+pub fn verify_str(expr_str: &str) -> VerifyOutcome {
+    match parse_implication(expr_str) {
+        Ok(expr) => verify_expr(&expr),
+        Err(e) => VerifyOutcome::Error(e),
+    }
+}
+
 // Main verification function that uses the parser module
-pub fn verify_str_implication(expr_str: &str) {
+pub fn verify_str_implication(
+    expr_str: &str,
+    timeout_ms: u32,
+    int_width: Option<IntWidth>,
+    verbose: bool,
+) -> Result<VerifyOutcome, ParseError> {
     // Z3 context and solver
     let cfg = Config::new();
     let ctx = Context::new(&cfg);
-    let mut solver = Solver::new(&ctx);
+    let mut solver = new_solver(&ctx, timeout_ms);
+    verify_str_implication_with(&ctx, &mut solver, expr_str, int_width, &[], &[], verbose)
+}
 
-    // Parse and process logical proposition
-    let parsed_expr = syn::parse_str::<syn::Expr>(expr_str).expect("Failed to parse expression");
-    let (z3_condition, vars) = z3_parser::generate_condition_and_vars(&ctx, &parsed_expr);
-    // Verify the condition
-    verify_condition(&mut solver, &z3_condition, &vars);
+#[cfg(test)]
+mod tests {
+    use super::set_z3_params;
+    use crate::{run_verification_str, VerificationOptions};
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn unknown_z3_params_are_reported() {
+        let error = set_z3_params(&params(&[("smt.random_seed", "0"), ("no.such_param", "1")]))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(error, "Unknown Z3 parameter: no.such_param");
+    }
+
+    #[test]
+    fn invalid_z3_param_values_are_reported() {
+        let solver = z3::get_global_param("smt.arith.solver");
+        let error = set_z3_params(&params(&[
+            ("smt.arith.solver", "2"),
+            ("smt.random_seed", "abc"),
+        ]))
+        .map(|_| ())
+        .unwrap_err();
+        assert_eq!(error, "Invalid Z3 parameter value: smt.random_seed=abc");
+        assert_eq!(z3::get_global_param("smt.arith.solver"), solver);
+    }
+
+    #[test]
+    fn z3_params_are_passed_to_the_solver_and_restored() {
+        let seed = z3::get_global_param("smt.random_seed");
+        let set = set_z3_params(&params(&[("smt.random_seed", "7")])).unwrap();
+        assert_eq!(
+            z3::get_global_param("smt.random_seed").as_deref(),
+            Some("7")
+        );
+        drop(set);
+        assert_eq!(z3::get_global_param("smt.random_seed"), seed);
+
+        let options = VerificationOptions {
+            z3_params: params(&[("smt.random_seed", "7")]),
+            ..VerificationOptions::default()
+        };
+        let report = run_verification_str(
+            "fn f(x: i32) -> i32 { pre!(x > 0); post!(x > 1); x }",
+            &options,
+        )
+        .unwrap();
+        assert!(report.paths[0].outcome.is_invalid());
+        assert_eq!(z3::get_global_param("smt.random_seed"), seed);
+    }
+
+    #[test]
+    fn valid_paths_come_with_a_proof_when_asked() {
+        let source = "fn f(x: i32) -> i32 { pre!(x > 0); post!(x > 0 || x < 0); x }
+fn g(x: i32) -> i32 { pre!(x > 0); post!(x > 1); x }";
+        let options = VerificationOptions {
+            proof: true,
+            ..VerificationOptions::default()
+        };
+        let report = run_verification_str(source, &options).unwrap();
+        assert!(!report.paths[0].proof.as_deref().unwrap().is_empty());
+        assert!(report.paths[1].outcome.is_invalid());
+        assert!(report.paths[1].proof.is_none());
+        let report = run_verification_str(source, &VerificationOptions::default()).unwrap();
+        assert!(report.paths[0].proof.is_none());
+    }
 }
@@ -1,7 +1,8 @@
 use crate::cfg_builder::node::CfgNode;
-use crate::cfg_builder::{builder::CfgBuilder, node::ConditionalExpr};
+use crate::cfg_builder::{builder::CfgBuilder, node::ConditionalExpr, ForRange};
+use crate::verifier::{field_key, ARRAY_STORE_FN};
 use petgraph::graph::NodeIndex;
-use proc_macro2::{Span, TokenStream, TokenTree};
+use proc_macro2::{Delimiter, Group, Ident, Span, TokenStream, TokenTree};
 use quote::quote;
 use std::collections::HashMap;
 /// This module handles variable substitution and logical condition chaining for Control Flow Graph (CFG) paths.
@@ -25,103 +26,215 @@ use std::collections::HashMap;
 /// - Relies on the `syn` crate for Rust syntax parsing.
 /// - Uses `petgraph` for traversing the CFG and maintaining node relationships.
 use syn::{
-    Block, Expr, ExprAssign, ExprBinary, ExprBlock, ExprCall, ExprIf, ExprMacro, ExprParen,
-    ExprUnary, Local, Macro, Stmt,
+    Block, Expr, ExprArray, ExprAssign, ExprBinary, ExprBlock, ExprCall, ExprCast, ExprField,
+    ExprGroup, ExprIf, ExprIndex, ExprMacro, ExprMethodCall, ExprParen, ExprRange, ExprReference,
+    ExprTuple, ExprUnary, Local, Macro, Stmt,
 };
 
 impl CfgBuilder {
     pub fn apply_wp_calculus(&self, paths: &[Vec<NodeIndex>]) -> Vec<String> {
-        let mut updated_postconditions = Vec::new();
-
-        for path in paths {
-            let mut variable_state = HashMap::new();
-            let mut working_condition: Option<syn::Expr> = None;
-
-            // Traverse the path in reverse (from postcondition up to precondition)
-            for &node_index in path.iter().rev() {
-                match &self.graph[node_index] {
-                    CfgNode::Statement(stmt_str, _stmt_option) => {
-                        if let Some((var, expr)) = self.parse_assignment(stmt_str) {
-                            // Check if there is a working condition that needs substitution
-                            if let Some(mut cond) = working_condition.take() {
-                                // Substitute once per variable
-                                cond = self.recursive_substitution(&cond, &var, &expr);
-                                working_condition = Some(cond);
-                            }
-
-                            // Track the current variable state for potential future substitution
-                            variable_state.insert(var.clone(), expr.clone());
-                            //println!("varState: {:?}", variable_state);
-                        }
+        paths
+            .iter()
+            .filter_map(|path| self.path_wp(path, None))
+            .collect()
+    }
+
+    // Working condition right before each node of the path, i.e. the weakest precondition of
+    // the rest of the path, for the nodes that have one
+    pub fn wp_conditions(&self, path: &[NodeIndex]) -> HashMap<NodeIndex, String> {
+        let mut conditions = HashMap::new();
+        self.path_wp(path, Some(&mut conditions));
+        conditions
+    }
+
+    // Implication of a basic path, recording the intermediate working conditions in 'trace'
+    fn path_wp(
+        &self,
+        path: &[NodeIndex],
+        mut trace: Option<&mut HashMap<NodeIndex, String>>,
+    ) -> Option<String> {
+        let mut variable_state = HashMap::new();
+        let mut working_condition: Option<syn::Expr> = None;
+
+        // Traverse the path in reverse (from postcondition up to precondition)
+        for &node_index in path.iter().rev() {
+            match &self.graph[node_index] {
+                CfgNode::Statement(stmt_str, stmt_option) => {
+                    let assignments = self.parse_assignment(stmt_str, stmt_option.as_ref());
+                    // Check if there is a working condition that needs substitution
+                    if let Some(cond) = working_condition.take() {
+                        working_condition = Some(match assignments.as_slice() {
+                            // Substitute once per variable
+                            [(var, expr)] => self.recursive_substitution(&cond, var, expr),
+                            _ => self.simultaneous_substitution(&cond, &assignments),
+                        });
                     }
-                    CfgNode::Condition(_, Some(conditional_expr)) => {
-                        // Don't substitute conditions but add them in the implication chain
-                        let is_false_branch = self.is_false_branch(&path, node_index);
-                        let updated_expr = if is_false_branch {
-                            // Negate the condition if we are on the false branch
-                            match conditional_expr {
-                                ConditionalExpr::If(expr_if) => ConditionalExpr::If(Box::new(
-                                    CfgBuilder::negate_condition(*expr_if.clone()),
-                                )),
-                                ConditionalExpr::While(expr_while) => ConditionalExpr::While(
-                                    Box::new(CfgBuilder::negate_condition(*expr_while.clone())),
-                                ),
-                                _ => conditional_expr.clone(),
-                            }
+
+                    // Track the current variable state for potential future substitution
+                    for (var, expr) in assignments {
+                        variable_state.insert(var, expr);
+                    }
+                    log::trace!("Assigned variables: {:?}", variable_state.keys());
+                }
+                // A loop over a range goes on while its variable is in the range, other loops
+                // don't tell anything about their variable
+                CfgNode::Condition(_, Some(ConditionalExpr::ForLoop(expr_for))) => {
+                    if let Some(range) = ForRange::of(expr_for) {
+                        let guard = if self.is_false_branch(path, node_index) {
+                            self.negate_branch_condition(range.in_range())
                         } else {
-                            match conditional_expr {
-                                ConditionalExpr::If(expr_if) => ConditionalExpr::If(Box::new(
-                                    Self::wrap_with_parens(*expr_if.clone()),
-                                )),
-                                ConditionalExpr::While(expr_while) => ConditionalExpr::While(
-                                    Box::new(Self::wrap_with_parens(*expr_while.clone())),
-                                ),
-                                _ => conditional_expr.clone(),
-                            }
+                            Self::wrap_with_parens(range.guard())
                         };
-
-                        let expr = updated_expr.to_syn_expr();
                         working_condition =
                             Some(if let Some(existing_cond) = working_condition.take() {
-                                syn::parse2(quote! { #expr >> #existing_cond })
+                                syn::parse2(quote! { #guard >> #existing_cond })
                                     .expect("Failed to parse condition implication")
                             } else {
-                                expr.clone()
-                            });
-                    }
-                    // TODO check what's extra here
-                    CfgNode::Postcondition(_, Some(expr)) | CfgNode::Invariant(_, Some(expr)) => {
-                        // Substitute variables in the postcondition/invariant and chain with the current condition
-                        let expr = expr.clone();
-                        working_condition =
-                            Some(if let Some(existing_cond) = working_condition.take() {
-                                syn::parse2(quote! { #expr >> #existing_cond })
-                                    .expect("Failed to parse conjunction")
-                            } else {
-                                expr
-                            });
-                    }
-                    CfgNode::Precondition(_, Some(expr)) => {
-                        // Chain with the current condition
-                        let expr = expr.clone();
-                        working_condition =
-                            Some(if let Some(existing_cond) = working_condition.take() {
-                                syn::parse2(quote! { #expr >> #existing_cond })
-                                    .expect("Failed to parse conjunction")
-                            } else {
-                                expr
+                                guard
                             });
                     }
-                    _ => {}
                 }
+                CfgNode::Condition(_, Some(conditional_expr)) => {
+                    // Don't substitute conditions but add them in the implication chain
+                    let is_false_branch = self.is_false_branch(&path, node_index);
+                    let updated_expr = if is_false_branch {
+                        // Negate the condition if we are on the false branch
+                        match conditional_expr {
+                            ConditionalExpr::If(expr_if) => ConditionalExpr::If(Box::new(
+                                self.negate_branch_condition(*expr_if.clone()),
+                            )),
+                            ConditionalExpr::While(expr_while) => ConditionalExpr::While(Box::new(
+                                self.negate_branch_condition(*expr_while.clone()),
+                            )),
+                            ConditionalExpr::Match(expr_match) => ConditionalExpr::Match(Box::new(
+                                self.negate_branch_condition(*expr_match.clone()),
+                            )),
+                            _ => conditional_expr.clone(),
+                        }
+                    } else {
+                        match conditional_expr {
+                            ConditionalExpr::If(expr_if) => ConditionalExpr::If(Box::new(
+                                Self::wrap_with_parens(*expr_if.clone()),
+                            )),
+                            ConditionalExpr::While(expr_while) => ConditionalExpr::While(Box::new(
+                                Self::wrap_with_parens(*expr_while.clone()),
+                            )),
+                            ConditionalExpr::Match(expr_match) => ConditionalExpr::Match(Box::new(
+                                Self::wrap_with_parens(*expr_match.clone()),
+                            )),
+                            _ => conditional_expr.clone(),
+                        }
+                    };
+
+                    let expr = updated_expr.to_syn_expr();
+                    working_condition =
+                        Some(if let Some(existing_cond) = working_condition.take() {
+                            syn::parse2(quote! { #expr >> #existing_cond })
+                                .expect("Failed to parse condition implication")
+                        } else {
+                            expr.clone()
+                        });
+                }
+                // TODO check what's extra here
+                // An assertion is the target of the paths ending at it and the assumption of
+                // the paths starting from it
+                CfgNode::Postcondition(_, Some(expr))
+                | CfgNode::Invariant(_, Some(expr))
+                | CfgNode::Assertion(_, Some(expr)) => {
+                    // Substitute variables in the postcondition/invariant and chain with the current condition
+                    let expr = expr.clone();
+                    working_condition =
+                        Some(if let Some(existing_cond) = working_condition.take() {
+                            syn::parse2(quote! { #expr >> #existing_cond })
+                                .expect("Failed to parse conjunction")
+                        } else {
+                            expr
+                        });
+                }
+                // Assumptions are hypotheses of the rest of the path, like branch conditions.
+                // So is a callee precondition the path goes through, its own path checks it.
+                CfgNode::Precondition(_, Some(expr))
+                | CfgNode::Assumption(_, Some(expr))
+                | CfgNode::CallPrecondition(_, Some(expr)) => {
+                    // Chain with the current condition
+                    let expr = expr.clone();
+                    working_condition =
+                        Some(if let Some(existing_cond) = working_condition.take() {
+                            syn::parse2(quote! { #expr >> #existing_cond })
+                                .expect("Failed to parse conjunction")
+                        } else {
+                            expr
+                        });
+                }
+                _ => {}
             }
 
-            if let Some(cond) = working_condition {
-                updated_postconditions.push(quote! { #cond }.to_string());
+            if let (Some(trace), Some(cond)) = (trace.as_mut(), &working_condition) {
+                trace.insert(
+                    node_index,
+                    Self::collapse_parens(quote! { #cond }).to_string(),
+                );
             }
         }
 
-        updated_postconditions
+        let cond = working_condition?;
+        // Paths starting at the precondition start at function entry
+        let from_entry = path
+            .first()
+            .is_some_and(|&n| matches!(self.graph[n], CfgNode::Precondition(_, _)));
+        let cond = self.resolve_old_values(&cond, from_entry);
+        // Constants are never assigned, so they can be replaced by their values last
+        let cond = self.substitute_constants(&cond, &self.constants);
+        Some(Self::collapse_parens(quote! { #cond }).to_string())
+    }
+
+    // Removes the parentheses substitutions pile up, without changing the meaning: '((e))' is
+    // '(e)', and a lone variable or literal needs none, unless they enclose the arguments of a
+    // call or macro. E.g. 'assert!(((a)) >= 0)' becomes 'assert!(a >= 0)'.
+    pub fn collapse_parens(tokens: TokenStream) -> TokenStream {
+        let tokens: Vec<TokenTree> = tokens
+            .into_iter()
+            .map(|token| match token {
+                TokenTree::Group(group) => {
+                    let mut collapsed =
+                        Group::new(group.delimiter(), Self::collapse_parens(group.stream()));
+                    collapsed.set_span(group.span());
+                    TokenTree::Group(collapsed)
+                }
+                token => token,
+            })
+            .collect();
+        let mut result: Vec<TokenTree> = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let mut token = token.clone();
+            // The inner parentheses of '((a, b))' may be a tuple argument, and those of '(())'
+            // the unit value
+            while let Some([nested]) = paren_contents(&token).as_deref() {
+                match paren_contents(nested) {
+                    Some(inner) if !inner.is_empty() && !has_top_level_comma(&inner) => {
+                        token = nested.clone();
+                    }
+                    _ => break,
+                }
+            }
+            let encloses_arguments = match result.last() {
+                Some(TokenTree::Ident(_) | TokenTree::Group(_)) => true,
+                Some(TokenTree::Punct(punct)) => punct.as_char() == '!',
+                _ => false,
+            };
+            // '(1).abs()' can't lose its parentheses
+            let is_receiver = matches!(tokens.get(i + 1), Some(TokenTree::Punct(punct)) if punct.as_char() == '.');
+            match paren_contents(&token).as_deref() {
+                Some([TokenTree::Ident(ident)]) if !encloses_arguments => {
+                    result.push(TokenTree::Ident(ident.clone()))
+                }
+                Some([TokenTree::Literal(literal)]) if !encloses_arguments && !is_receiver => {
+                    result.push(TokenTree::Literal(literal.clone()))
+                }
+                _ => result.push(token),
+            }
+        }
+        result.into_iter().collect()
     }
 
     fn is_false_branch(&self, path: &[NodeIndex], current_node: NodeIndex) -> bool {
@@ -137,7 +250,12 @@ impl CfgBuilder {
         false
     }
 
+    // Parenthesizes an expression so it keeps its meaning wherever it's spliced, e.g. 'n + 1'
+    // for 'i' in 'i * 2' gives '(n + 1) * 2' and not 'n + 1 * 2'
     fn wrap_with_parens(expr: Expr) -> Expr {
+        if let Expr::Paren(_) = expr {
+            return expr;
+        }
         Expr::Paren(ExprParen {
             attrs: Vec::new(),
             paren_token: syn::token::Paren(Span::call_site()),
@@ -153,21 +271,45 @@ impl CfgBuilder {
     ) -> Expr {
         // println!("Substituting in expr: {:?}", quote! {#expr});
         //self.print_expr_details(expr);
-        let replacement = &Expr::Paren(ExprParen {
-            attrs: Vec::new(),
-            paren_token: syn::token::Paren(Span::call_site()),
-            expr: Box::new(replacement_without_paren.clone()),
-        });
+        let replacement = &Self::wrap_with_parens(replacement_without_paren.clone());
 
         match expr {
             Expr::Path(expr_path) => {
                 if expr_path.path.is_ident(var) {
-                    // println!("Substituting {} with {}", var, quote! {#replacement});
+                    log::trace!("Substituting {} with {}", var, quote! {#replacement});
                     replacement.clone()
                 } else {
                     expr.clone()
                 }
             }
+            // A field is a variable keyed by its path, 'self.len' is replaced as a whole
+            Expr::Field(expr_field) => {
+                if field_key(expr).as_deref() == Some(var) {
+                    replacement.clone()
+                } else {
+                    Expr::Field(ExprField {
+                        base: Box::new(self.recursive_substitution(
+                            &expr_field.base,
+                            var,
+                            replacement,
+                        )),
+                        ..expr_field.clone()
+                    })
+                }
+            }
+            // 'old!(...)' refers to the value at function entry and is never substituted
+            Expr::Macro(expr_macro) if expr_macro.mac.path.is_ident("old") => expr.clone(),
+            // A quantifier binding 'var' shadows it
+            Expr::Macro(expr_macro)
+                if (expr_macro.mac.path.is_ident("forall")
+                    || expr_macro.mac.path.is_ident("exists"))
+                    && matches!(
+                        expr_macro.mac.tokens.clone().into_iter().next(),
+                        Some(TokenTree::Ident(bound)) if bound == var
+                    ) =>
+            {
+                expr.clone()
+            }
             Expr::Macro(expr_macro) => {
                 let new_tokens =
                     self.substitute_in_token_stream(&expr_macro.mac.tokens, var, replacement);
@@ -207,11 +349,69 @@ impl CfgBuilder {
                     .map(|arg| self.recursive_substitution(arg, var, replacement))
                     .collect(),
             }),
+            Expr::MethodCall(method_call) => Expr::MethodCall(ExprMethodCall {
+                receiver: Box::new(self.recursive_substitution(
+                    &method_call.receiver,
+                    var,
+                    replacement,
+                )),
+                args: method_call
+                    .args
+                    .iter()
+                    .map(|arg| self.recursive_substitution(arg, var, replacement))
+                    .collect(),
+                ..method_call.clone()
+            }),
             Expr::Unary(unary) => Expr::Unary(ExprUnary {
                 attrs: unary.attrs.clone(),
                 op: unary.op.clone(),
                 expr: Box::new(self.recursive_substitution(&unary.expr, var, replacement)),
             }),
+            Expr::Index(index) => Expr::Index(ExprIndex {
+                attrs: index.attrs.clone(),
+                expr: Box::new(self.recursive_substitution(&index.expr, var, replacement)),
+                bracket_token: index.bracket_token,
+                index: Box::new(self.recursive_substitution(&index.index, var, replacement)),
+            }),
+            Expr::Cast(cast) => Expr::Cast(ExprCast {
+                expr: Box::new(self.recursive_substitution(&cast.expr, var, replacement)),
+                ..cast.clone()
+            }),
+            Expr::Reference(reference) => Expr::Reference(ExprReference {
+                expr: Box::new(self.recursive_substitution(&reference.expr, var, replacement)),
+                ..reference.clone()
+            }),
+            Expr::Group(group) => Expr::Group(ExprGroup {
+                expr: Box::new(self.recursive_substitution(&group.expr, var, replacement)),
+                ..group.clone()
+            }),
+            Expr::Tuple(tuple) => Expr::Tuple(ExprTuple {
+                elems: tuple
+                    .elems
+                    .iter()
+                    .map(|elem| self.recursive_substitution(elem, var, replacement))
+                    .collect(),
+                ..tuple.clone()
+            }),
+            Expr::Array(array) => Expr::Array(ExprArray {
+                elems: array
+                    .elems
+                    .iter()
+                    .map(|elem| self.recursive_substitution(elem, var, replacement))
+                    .collect(),
+                ..array.clone()
+            }),
+            Expr::Range(range) => Expr::Range(ExprRange {
+                from: range
+                    .from
+                    .as_ref()
+                    .map(|from| Box::new(self.recursive_substitution(from, var, replacement))),
+                to: range
+                    .to
+                    .as_ref()
+                    .map(|to| Box::new(self.recursive_substitution(to, var, replacement))),
+                ..range.clone()
+            }),
             Expr::Paren(paren) => Expr::Paren(ExprParen {
                 attrs: paren.attrs.clone(),
                 paren_token: paren.paren_token,
@@ -282,29 +482,47 @@ impl CfgBuilder {
         }
     }
 
-    fn parse_assignment(&self, stmt: &str) -> Option<(String, syn::Expr)> {
-        // Debug print the input statement
-        // Ensure the statement ends with a semicolon
-        let stmt = if stmt.trim_end().ends_with(';') {
-            stmt.to_string()
-        } else {
-            format!("{};", stmt.trim_end())
-        };
-
-        //println!("Parsing statement: {}", stmt);
+    // Substitute several variables at once, as for 'let (a, b) = (b, a);'. Each variable is first
+    // replaced by a placeholder, so values mentioning the other variables aren't substituted again.
+    pub fn simultaneous_substitution(&self, expr: &Expr, assignments: &[(String, Expr)]) -> Expr {
+        let placeholder = |var: &str| format!("__tuple_{}", var);
+        let renamed = assignments.iter().fold(expr.clone(), |cond, (var, _)| {
+            let placeholder_expr = syn::parse_str::<Expr>(&placeholder(var))
+                .expect("Failed to parse substitution placeholder");
+            self.recursive_substitution(&cond, var, &placeholder_expr)
+        });
+        assignments.iter().fold(renamed, |cond, (var, value)| {
+            self.recursive_substitution(&cond, &placeholder(var), value)
+        })
+    }
 
-        // Parse the statement into a syn::Stmt
-        let stmt: syn::Stmt = match syn::parse_str(&stmt) {
-            Ok(s) => s,
-            Err(_e) => {
-                // println!("Failed to parse statement: {}", e);
-                return None;
+    // Variables assigned by a statement and their new values, several for tuple destructuring.
+    // The statement kept in the node is used when there is one, so the values keep their spans;
+    // otherwise the label is parsed.
+    fn parse_assignment(
+        &self,
+        stmt_str: &str,
+        stmt: Option<&syn::Stmt>,
+    ) -> Vec<(String, syn::Expr)> {
+        let stmt = match stmt {
+            Some(stmt) => stmt.clone(),
+            None => {
+                // Ensure the statement ends with a semicolon
+                let stmt_str = if stmt_str.trim_end().ends_with(';') {
+                    stmt_str.to_string()
+                } else {
+                    format!("{};", stmt_str.trim_end())
+                };
+                match syn::parse_str(&stmt_str) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::trace!("Failed to parse statement {}: {}", stmt_str, e);
+                        return Vec::new();
+                    }
+                }
             }
         };
 
-        // Debug print the parsed statement
-        //println!("Parsed syn::Stmt: {:#?}", &stmt);
-
         if let syn::Stmt::Expr(syn::Expr::Assign(assign))
         | syn::Stmt::Semi(syn::Expr::Assign(assign), _) = stmt.clone()
         {
@@ -312,41 +530,121 @@ impl CfgBuilder {
             if let syn::Expr::Path(path) = *assign.left {
                 if let Some(ident) = path.path.get_ident() {
                     let var = ident.to_string();
-                    // println!("Found assignment: {} = {:?}", var, *assign.right);
-                    return Some((var, *assign.right));
+                    let value = *assign.right;
+                    log::trace!("Found assignment: {} = {}", var, quote! {#value});
+                    return vec![(var, value)];
                 }
+            } else if let syn::Expr::Field(_) = *assign.left {
+                // Handle field assignments like 'self.len = n;'
+                return field_key(&assign.left)
+                    .map(|var| (var, *assign.right))
+                    .into_iter()
+                    .collect();
+            } else if let syn::Expr::Index(index) = *assign.left {
+                // Handle element assignments like 'a[i] = v;'
+                return Self::parse_array_store(&index, *assign.right)
+                    .into_iter()
+                    .collect();
             }
         } else if let syn::Stmt::Expr(syn::Expr::AssignOp(assign_op))
         | syn::Stmt::Semi(syn::Expr::AssignOp(assign_op), _) = stmt.clone()
         {
             // Handle compound assignments like 'count += 1;'
-            if let syn::Expr::Path(path) = *assign_op.left {
+            let Some(right_expr) = Self::compound_value(&assign_op) else {
+                return Vec::new();
+            };
+            if let syn::Expr::Path(path) = &*assign_op.left {
                 if let Some(ident) = path.path.get_ident() {
                     let var = ident.to_string();
-                    let right_expr = syn::Expr::Binary(syn::ExprBinary {
-                        attrs: vec![],
-                        left: Box::new(syn::Expr::Path(path.clone())),
-                        op: assign_op.op.clone(),
-                        right: assign_op.right.clone(),
-                    });
-                    // println!("Found compound assignment: {} = {:?}", var, right_expr);
-                    return Some((var, right_expr));
+                    log::trace!(
+                        "Found compound assignment: {} = {}",
+                        var,
+                        quote! {#right_expr}
+                    );
+                    return vec![(var, right_expr)];
                 }
+            } else if let syn::Expr::Field(_) = &*assign_op.left {
+                return field_key(&assign_op.left)
+                    .map(|var| (var, right_expr))
+                    .into_iter()
+                    .collect();
+            } else if let syn::Expr::Index(index) = &*assign_op.left {
+                // Handle element updates like 'a[i] += 1;' as 'a[i] = a[i] + 1;'
+                return Self::parse_array_store(index, right_expr)
+                    .into_iter()
+                    .collect();
             }
         }
         // Handle 'let' like 'let mut sum = 0;'
         else if let syn::Stmt::Local(local) = stmt.clone() {
-            if let syn::Pat::Ident(pat_ident) = &local.pat {
-                // If we have an identifier (sum)
-                let var = pat_ident.ident.to_string(); // Take var identifier (string)
-                if let Some((_, expr)) = &local.init {
-                    return Some((var, *expr.clone())); // Return the id and literal it's initialized to (expr)
-                }
+            if let Some((_, expr)) = &local.init {
+                return Self::destructure(&local.pat, expr);
             }
         }
 
         // println!("No valid assignment found in statement: {:#?}", stmt);
-        None
+        Vec::new()
+    }
+
+    // Bindings of a 'let' pattern: 'let (a, b) = (1, 2);' binds 'a' to '1' and 'b' to '2'.
+    // Tuple patterns bound to anything but a tuple of the same arity bind nothing
+    // (the builder warns about them).
+    fn destructure(pat: &syn::Pat, expr: &Expr) -> Vec<(String, syn::Expr)> {
+        match (pat, expr) {
+            (syn::Pat::Ident(pat_ident), _) => vec![(pat_ident.ident.to_string(), expr.clone())],
+            (syn::Pat::Type(pat_type), _) => Self::destructure(&pat_type.pat, expr),
+            (_, Expr::Paren(paren)) => Self::destructure(pat, &paren.expr),
+            (syn::Pat::Tuple(pat_tuple), Expr::Tuple(expr_tuple))
+                if pat_tuple.elems.len() == expr_tuple.elems.len() =>
+            {
+                pat_tuple
+                    .elems
+                    .iter()
+                    .zip(expr_tuple.elems.iter())
+                    .flat_map(|(elem_pat, elem_expr)| Self::destructure(elem_pat, elem_expr))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    // Value assigned by a compound assignment: 'x += e' assigns 'x + (e)'
+    fn compound_value(assign_op: &syn::ExprAssignOp) -> Option<syn::Expr> {
+        let op = match assign_op.op {
+            syn::BinOp::AddEq(_) => syn::BinOp::Add(Default::default()),
+            syn::BinOp::SubEq(_) => syn::BinOp::Sub(Default::default()),
+            syn::BinOp::MulEq(_) => syn::BinOp::Mul(Default::default()),
+            syn::BinOp::DivEq(_) => syn::BinOp::Div(Default::default()),
+            syn::BinOp::RemEq(_) => syn::BinOp::Rem(Default::default()),
+            syn::BinOp::ShlEq(_) => syn::BinOp::Shl(Default::default()),
+            syn::BinOp::ShrEq(_) => syn::BinOp::Shr(Default::default()),
+            syn::BinOp::BitAndEq(_) => syn::BinOp::BitAnd(Default::default()),
+            syn::BinOp::BitOrEq(_) => syn::BinOp::BitOr(Default::default()),
+            syn::BinOp::BitXorEq(_) => syn::BinOp::BitXor(Default::default()),
+            _ => return None,
+        };
+        Some(syn::Expr::Binary(syn::ExprBinary {
+            attrs: vec![],
+            left: assign_op.left.clone(),
+            op,
+            right: Box::new(Self::wrap_with_parens(*assign_op.right.clone())),
+        }))
+    }
+
+    // 'a[i] = v' assigns 'a' the array with element 'i' replaced by 'v', and 'm[i][j] = v'
+    // assigns 'm' the array with row 'i' replaced by 'm[i]' with element 'j' replaced by 'v'
+    fn parse_array_store(index: &syn::ExprIndex, value: syn::Expr) -> Option<(String, syn::Expr)> {
+        let store_fn = Ident::new(ARRAY_STORE_FN, Span::call_site());
+        let (array, index_expr) = (&index.expr, &index.index);
+        let store_expr = syn::parse_quote!(#store_fn(#array, #index_expr, #value));
+        match &**array {
+            syn::Expr::Path(path) => path
+                .path
+                .get_ident()
+                .map(|ident| (ident.to_string(), store_expr)),
+            syn::Expr::Index(row) => Self::parse_array_store(row, store_expr),
+            _ => None,
+        }
     }
 
     /*fn print_expr_details(&self, expr: &Expr) {
@@ -359,47 +657,424 @@ impl CfgBuilder {
         var: &str,
         replacement: &Expr,
     ) -> TokenStream {
-        // Convert the replacement expression to a string
-        let replacement_string = quote! { #replacement }.to_string();
-        // Parse the string back into a TokenStream
-        let replacement_token_stream: TokenStream = replacement_string
-            .parse()
-            .expect("Failed to parse replacement string");
-
-        tokens
-            .clone()
-            .into_iter()
-            .flat_map(|tt| {
-                match &tt {
-                    TokenTree::Ident(ident) if ident.to_string() == var => replacement_token_stream
-                        .clone()
-                        .into_iter()
-                        .collect::<Vec<_>>()
-                        .into_iter(),
-                    TokenTree::Group(group) => {
-                        let new_stream =
-                            self.substitute_in_token_stream(&group.stream(), var, replacement);
-                        // println!("Replaced group: {:#?}", new_stream);
-                        vec![TokenTree::Group(proc_macro2::Group::new(
-                            group.delimiter(),
-                            new_stream,
-                        ))]
-                        .into_iter()
-                    }
-                    TokenTree::Punct(_punct) => {
-                        // println!("Punctuation: {}", punct);
-                        vec![tt.clone()].into_iter()
-                    }
-                    TokenTree::Literal(_literal) => {
-                        // println!("Literal: {}", literal);
-                        vec![tt.clone()].into_iter()
-                    }
-                    _ => {
-                        // println!("Other token: {:#?}", tt);
-                        vec![tt.clone()].into_iter()
+        // The replacement keeps its own spans, so errors point at where the value was written.
+        // Macro arguments are only tokens, so the parentheses are what keeps the precedence.
+        let replacement = Self::wrap_with_parens(replacement.clone());
+        let replacement_token_stream = quote! { #replacement };
+
+        let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+        let mut result = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            // Keep 'old!(...)' untouched, it refers to the value at function entry, and
+            // so are quantifiers binding 'var' since it's a different variable inside them
+            if Self::old_macro_at(&tokens, i).is_some()
+                || Self::quantifier_binding_at(&tokens, i).as_deref() == Some(var)
+            {
+                result.extend_from_slice(&tokens[i..i + 3]);
+                i += 3;
+                continue;
+            }
+            // Fields and methods named like 'var' aren't the variable, e.g. 'len' in 'v.len()',
+            // and neither are path segments, e.g. 'MAX' in 'i32::MAX'
+            let after_dot = (i > 0 && Self::is_member_dot(&tokens, i - 1))
+                || Self::is_after_path_separator(&tokens, i);
+            if !after_dot {
+                if let Some(len) = Self::field_tokens_at(&tokens, i, var) {
+                    result.extend(replacement_token_stream.clone());
+                    i += len;
+                    continue;
+                }
+            }
+            match &tokens[i] {
+                TokenTree::Ident(ident) if *ident == var && !after_dot => {
+                    result.extend(replacement_token_stream.clone())
+                }
+                TokenTree::Group(group) => {
+                    let new_stream =
+                        self.substitute_in_token_stream(&group.stream(), var, &replacement);
+                    let mut new_group = proc_macro2::Group::new(group.delimiter(), new_stream);
+                    new_group.set_span(group.span());
+                    result.push(TokenTree::Group(new_group));
+                }
+                tt => result.push(tt.clone()),
+            }
+            i += 1;
+        }
+        result.into_iter().collect()
+    }
+
+    // A '.' accessing a field or method, unlike the ones of a range like '0..n'
+    fn is_member_dot(tokens: &[TokenTree], i: usize) -> bool {
+        let is_dot =
+            |tt: Option<&TokenTree>| matches!(tt, Some(TokenTree::Punct(p)) if p.as_char() == '.');
+        is_dot(tokens.get(i))
+            && !is_dot(i.checked_sub(1).and_then(|j| tokens.get(j)))
+            && !is_dot(tokens.get(i + 1))
+    }
+
+    // Whether the token at 'i' follows a '::'
+    fn is_after_path_separator(tokens: &[TokenTree], i: usize) -> bool {
+        let is_colon =
+            |j: usize| matches!(tokens.get(j), Some(TokenTree::Punct(p)) if p.as_char() == ':');
+        i >= 2 && is_colon(i - 1) && is_colon(i - 2)
+    }
+
+    // Number of tokens of the field access 'var' (like 'self.len') at 'i', if it's there and
+    // isn't a method call like 'self.len()'
+    fn field_tokens_at(tokens: &[TokenTree], i: usize, var: &str) -> Option<usize> {
+        let segments: Vec<&str> = var.split('.').collect();
+        if segments.len() < 2 {
+            return None;
+        }
+        let len = 2 * segments.len() - 1;
+        let matches = tokens.get(i..i + len)?.iter().enumerate().all(|(j, tt)| {
+            if j % 2 == 1 {
+                matches!(tt, TokenTree::Punct(p) if p.as_char() == '.')
+            } else {
+                tt.to_string() == segments[j / 2]
+            }
+        });
+        let is_call = matches!(
+            tokens.get(i + len),
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis
+        );
+        (matches && !is_call).then_some(len)
+    }
+
+    // Returns the argument group if the tokens at 'i' are 'old!(...)'
+    fn old_macro_at(tokens: &[TokenTree], i: usize) -> Option<&proc_macro2::Group> {
+        match tokens.get(i..i + 3)? {
+            [TokenTree::Ident(ident), TokenTree::Punct(bang), TokenTree::Group(group)]
+                if ident == "old" && bang.as_char() == '!' =>
+            {
+                Some(group)
+            }
+            _ => None,
+        }
+    }
+
+    // Returns the bound variable if the tokens at 'i' are 'forall!(k, ...)' or 'exists!(k, ...)'
+    fn quantifier_binding_at(tokens: &[TokenTree], i: usize) -> Option<String> {
+        match tokens.get(i..i + 3)? {
+            [TokenTree::Ident(ident), TokenTree::Punct(bang), TokenTree::Group(group)]
+                if (ident == "forall" || ident == "exists") && bang.as_char() == '!' =>
+            {
+                match group.stream().into_iter().next()? {
+                    TokenTree::Ident(bound) => Some(bound.to_string()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Replace every 'old!(e)' with the value of 'e' at function entry. On paths starting
+    // at the precondition nothing has been assigned yet, so that's 'e' itself; on other
+    // paths (e.g. from a loop invariant) each variable becomes a fresh '__old_<var>' constant
+    pub fn resolve_old_values(&self, expr: &Expr, from_entry: bool) -> Expr {
+        let tokens = Self::resolve_old_in_token_stream(&quote! { #expr }, from_entry);
+        syn::parse2(tokens).expect("Failed to parse condition after resolving old! values")
+    }
+
+    fn resolve_old_in_token_stream(tokens: &TokenStream, from_entry: bool) -> TokenStream {
+        let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+        let mut result = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            if let Some(group) = Self::old_macro_at(&tokens, i) {
+                let inner = if from_entry {
+                    group.stream()
+                } else {
+                    Self::rename_to_old(&group.stream())
+                };
+                let inner = Self::resolve_old_in_token_stream(&inner, from_entry);
+                result.push(TokenTree::Group(proc_macro2::Group::new(
+                    Delimiter::Parenthesis,
+                    inner,
+                )));
+                i += 3;
+                continue;
+            }
+            match &tokens[i] {
+                TokenTree::Group(group) => {
+                    let new_stream = Self::resolve_old_in_token_stream(&group.stream(), from_entry);
+                    let mut new_group = proc_macro2::Group::new(group.delimiter(), new_stream);
+                    new_group.set_span(group.span());
+                    result.push(TokenTree::Group(new_group));
+                }
+                tt => result.push(tt.clone()),
+            }
+            i += 1;
+        }
+        result.into_iter().collect()
+    }
+
+    // Renames the variables of 'old!(...)', leaving out method and field names ('.len()') and
+    // macro names, like 'rename_tokens' of the SSA pass
+    fn rename_to_old(tokens: &TokenStream) -> TokenStream {
+        let trees: Vec<TokenTree> = tokens.clone().into_iter().collect();
+        trees
+            .iter()
+            .enumerate()
+            .map(|(i, tt)| match tt {
+                TokenTree::Ident(ident) if ident != "true" && ident != "false" => {
+                    let after_dot =
+                        i > 0 && matches!(&trees[i - 1], TokenTree::Punct(p) if p.as_char() == '.');
+                    let is_macro =
+                        matches!(trees.get(i + 1), Some(TokenTree::Punct(p)) if p.as_char() == '!');
+                    if after_dot || is_macro {
+                        tt.clone()
+                    } else {
+                        TokenTree::Ident(Ident::new(&format!("__old_{}", ident), ident.span()))
                     }
                 }
+                TokenTree::Group(group) => TokenTree::Group(proc_macro2::Group::new(
+                    group.delimiter(),
+                    Self::rename_to_old(&group.stream()),
+                )),
+                tt => tt.clone(),
             })
             .collect()
     }
 }
+
+// Tokens between the parentheses of a parenthesized group
+fn paren_contents(token: &TokenTree) -> Option<Vec<TokenTree>> {
+    match token {
+        TokenTree::Group(group) if group.delimiter() == Delimiter::Parenthesis => {
+            Some(group.stream().into_iter().collect())
+        }
+        _ => None,
+    }
+}
+
+fn has_top_level_comma(tokens: &[TokenTree]) -> bool {
+    tokens
+        .iter()
+        .any(|token| matches!(token, TokenTree::Punct(punct) if punct.as_char() == ','))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg_builder::builder::CfgBuilder;
+    use crate::{path_outcomes, VerifyOutcome};
+
+    #[test]
+    fn substituted_sums_keep_their_precedence() {
+        let source = "fn f(n: i32) -> i32 { pre!(n >= 0); let i = n + 1; \
+            post!(i * 2 == 2 * n + 2 && forall!(k, (k == i * 2) >> (k == 2 * n + 2))); i }";
+        // Inside macro arguments too, where the substitution works on tokens
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            ["pre ! (n >= 0) >> post ! ((n + 1) * 2 == 2 * n + 2 && \
+              forall ! (k , (k == (n + 1) * 2) >> (k == 2 * n + 2)))"]
+        );
+        assert!(matches!(path_outcomes(source)[..], [VerifyOutcome::Valid]));
+    }
+
+    #[test]
+    fn redundant_parentheses_are_collapsed() {
+        let pairs = [
+            ("((a + b)) * c", "(a + b) * c"),
+            ("(((x))) >= (0)", "x >= 0"),
+            ("assert!(((a)) >= 0)", "assert!(a >= 0)"),
+            // Call arguments, tuples, unit values and literal receivers keep theirs
+            ("f(a) + g((a, b)) + (())", "f(a) + g((a, b)) + (())"),
+            ("(1).abs() + (-1)", "(1).abs() + (-1)"),
+        ];
+        for (input, output) in pairs {
+            let tokens: proc_macro2::TokenStream = input.parse().unwrap();
+            let expected: proc_macro2::TokenStream = output.parse().unwrap();
+            assert_eq!(
+                CfgBuilder::collapse_parens(tokens).to_string(),
+                expected.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn long_paths_have_shallow_parentheses() {
+        let increments = "x = (x + 1); ".repeat(50);
+        let source = format!(
+            "fn f(n: i32) -> i32 {{ pre!(n >= 0); let mut x = (n); {}post!(((x)) == n + 50); x }}",
+            increments
+        );
+        let condition = &CfgBuilder::verification_conditions(&source).unwrap()[0];
+        let depth = condition
+            .chars()
+            .scan(0i32, |depth, c| {
+                *depth += match c {
+                    '(' => 1,
+                    ')' => -1,
+                    _ => 0,
+                };
+                Some(*depth)
+            })
+            .max()
+            .unwrap();
+        // One level per substituted sum, none piled up around them
+        assert!(depth <= 52, "{}", condition);
+        assert!(matches!(path_outcomes(&source)[..], [VerifyOutcome::Valid]));
+    }
+
+    #[test]
+    fn old_values_are_the_entry_values() {
+        let source = "fn inc(mut x: i32) -> i32 { pre!(x >= 0); x = x + 1; \
+                      post!(x == old!(x) + 1 && x > old!(x)); x }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            ["pre ! (x >= 0) >> post ! ((x + 1) == x + 1 && (x + 1) > x)"]
+        );
+        assert!(matches!(path_outcomes(source)[..], [VerifyOutcome::Valid]));
+        let outcomes = path_outcomes(
+            "fn inc(mut x: i32) -> i32 { pre!(x >= 0); x = x + 1; post!(x == old!(x)); x }",
+        );
+        assert!(outcomes[0].is_invalid());
+    }
+
+    #[test]
+    fn old_values_keep_method_names() {
+        let source = "fn f(mut v: Vec<i32>, n: usize) -> usize { pre!(n >= 0); let mut i = 0; \
+            while i < n { invariant!(v.len() == old!(v.len()) && i <= n); v[0] = 1; i = i + 1; } \
+            post!(v.len() == old!(v.len())); i }";
+        // Only the variable refers to its entry value, 'len' stays the method
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap()[1],
+            "invariant ! (v . len () == (__old_v . len ()) && i <= n) >> ! (i < n) >> \
+             post ! (v . len () == (__old_v . len ()))"
+        );
+        let outcomes = path_outcomes(source);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn element_assignments_store_into_the_array() {
+        let outcomes = path_outcomes(
+            "fn f(mut a: [i32; 10], n: usize) { pre!(n >= 0 && n <= 10); let mut i = 0; \
+             while i < n { invariant!(i <= n && forall!(k, (0 <= k && k < i) >> (a[k] == 0))); \
+             a[i] = 0; i = i + 1; } post!(forall!(k, (0 <= k && k < n) >> (a[k] == 0))); }",
+        );
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+        // Another element is only unchanged if it's at another index
+        let outcomes = path_outcomes(
+            "fn f(mut a: [i32; 3], i: usize, j: usize) { pre!(a[j] == 5); a[i] = 0; \
+             post!(a[j] == 5); }",
+        );
+        assert!(outcomes[0].is_invalid());
+    }
+
+    #[test]
+    fn nested_element_assignments_store_into_the_row() {
+        let source =
+            "fn f(mut m: [[i32; 3]; 3], n: usize) { pre!(n >= 0 && n <= 3 && m[1][0] == 5); \
+            let mut j = 0; while j < n { \
+            invariant!(j <= n && m[1][0] == 5 && forall!(k, (0 <= k && k < j) >> (m[0][k] == 0))); \
+            m[0][j] = 0; j = j + 1; } \
+            post!(m[1][0] == 5 && forall!(k, (0 <= k && k < n) >> (m[0][k] == 0))); }";
+        let outcomes = path_outcomes(source);
+        assert_eq!(outcomes.len(), 3);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+        assert_eq!(
+            CfgBuilder::verification_conditions(
+                "fn f(mut m: [[i32; 3]; 3], i: usize, j: usize) { pre!(true); m[i][j] = 0; \
+                 post!(m[i][j] == 0); }"
+            )
+            .unwrap(),
+            ["pre ! (true) >> post ! ((__store (m , i , __store (m [i] , j , 0))) [i] [j] == 0)"]
+        );
+    }
+
+    #[test]
+    fn compound_element_assignments_update_the_element() {
+        let outcomes = path_outcomes(
+            "fn f(mut a: [i32; 3], i: usize) { pre!(a[i] >= 0); a[i] += 1; a[i] *= 2; \
+             post!(a[i] >= 2 && a[i] % 2 == 0); }",
+        );
+        assert!(matches!(outcomes[..], [VerifyOutcome::Valid]));
+    }
+
+    #[test]
+    fn false_branches_of_else_less_ifs_are_negated() {
+        // 'y >= 0' only holds on the false branch because 'x < 0' doesn't
+        let source = "fn f(x: i32) -> i32 { pre!(true); let mut y = x; if x < 0 { y = 0; } \
+                      post!(y >= 0); y }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            [
+                "pre ! (true) >> ! (x < 0) >> post ! (x >= 0)",
+                "pre ! (true) >> (x < 0) >> post ! (0 >= 0)",
+            ]
+        );
+        assert!(path_outcomes(source)
+            .iter()
+            .all(|outcome| matches!(outcome, VerifyOutcome::Valid)));
+    }
+
+    #[test]
+    fn tuple_patterns_bind_each_element() {
+        let source = "fn f(x: i32) -> i32 { pre!(x > 0); let (a, b) = (x, x + 1); \
+                      post!(b - a == 1 && a > 0); a }";
+        assert_eq!(
+            CfgBuilder::verification_conditions(source).unwrap(),
+            ["pre ! (x > 0) >> post ! ((x + 1) - x == 1 && x > 0)"]
+        );
+        assert!(matches!(path_outcomes(source)[..], [VerifyOutcome::Valid]));
+        // Mismatched arities leave the bindings unknown instead of panicking
+        assert_eq!(
+            CfgBuilder::verification_conditions(
+                "fn f() -> i32 { pre!(true); let (a, b) = (1, 2, 3); post!(a == 1); a }"
+            )
+            .unwrap(),
+            ["pre ! (true) >> post ! (a == 1)"]
+        );
+    }
+
+    #[test]
+    fn assignments_are_substituted_into_casts() {
+        let outcomes = path_outcomes(
+            "fn f(mut y: i32) { pre!(y == 10); y = y + 1; let mut r = 0; \
+             if y as i64 > 10 { r = 1; } post!(r == 0); r }",
+        );
+        assert!(outcomes.iter().any(|outcome| outcome.is_invalid()));
+    }
+
+    #[test]
+    fn assignments_are_substituted_into_references() {
+        let outcomes = path_outcomes(
+            "fn f(mut y: i32) { pre!(y == 10); y = y + 1; let mut r = 0; \
+             if *&y > 10 { r = 1; } post!(r == 0); r }",
+        );
+        assert!(outcomes.iter().any(|outcome| outcome.is_invalid()));
+    }
+
+    #[test]
+    fn assignments_are_substituted_into_tuples() {
+        let outcomes = path_outcomes(
+            "fn f(mut a: i32, b: i32) { pre!(a == 10 && b == 10); a = a + 1; let mut r = 0; \
+             if (a, b) != (10, 10) { r = 1; } post!(r == 0); r }",
+        );
+        assert!(outcomes.iter().any(|outcome| outcome.is_invalid()));
+    }
+
+    #[test]
+    fn assignments_are_substituted_into_arrays_and_ranges() {
+        let conditions = CfgBuilder::verification_conditions(
+            "fn f(mut y: i32) { pre!(y == 10); y = y + 1; \
+             post!([y, 0][0] == 11 && (0..y).contains(&10)); }",
+        )
+        .unwrap();
+        assert_eq!(
+            conditions,
+            ["pre ! (y == 10) >> post ! ([(y + 1) , 0] [0] == 11 && (0 .. (y + 1)) . contains (& 10))"]
+        );
+    }
+}